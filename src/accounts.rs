@@ -0,0 +1,72 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// One account of the --accounts file, the other options apply to all accounts
+pub struct AccountConfig {
+    /// IMAP server, with the port if it is not 993
+    pub server: String,
+    pub account: String,
+    /// Otherwise read from password_file, or taken from the same sources as for a single account
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// Path where to store the reports of this account
+    pub path: PathBuf,
+    #[serde(default)]
+    pub state: Option<PathBuf>,
+    #[serde(default)]
+    pub error_log: Option<PathBuf>,
+    /// Folders to process, the ones given on the command line if empty
+    #[serde(default)]
+    pub folders: Vec<String>,
+}
+
+impl AccountConfig {
+    pub fn load_all(path: &Path) -> Result<Vec<AccountConfig>> {
+        let accounts: Vec<AccountConfig> = serde_json::from_reader(File::open(path)?)?;
+        if accounts.is_empty() {
+            return Err(anyhow!("No accounts configured"));
+        }
+        Ok(accounts)
+    }
+
+    pub fn password(&self) -> Result<Option<String>> {
+        if let Some(path) = &self.password_file {
+            let password = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Could not read {}: {}", path.display(), e))?;
+            return Ok(Some(String::from(
+                password.trim_end_matches(&['\r', '\n'][..]),
+            )));
+        }
+        Ok(self.password.clone())
+    }
+
+    pub fn label(&self) -> String {
+        format!("'{}' on {}", self.account, self.server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_minimal_account() {
+        let json = r#"[{"server": "mail.example.com", "account": "dmarc", "path": "/srv/dmarc"}]"#;
+        let accounts: Vec<AccountConfig> = serde_json::from_str(json).unwrap();
+        assert_eq!(accounts[0].label(), "'dmarc' on mail.example.com");
+        assert!(accounts[0].folders.is_empty());
+        assert_eq!(accounts[0].password().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let json = r#"[{"server": "mail", "account": "dmarc", "path": "/srv", "pasword": "x"}]"#;
+        assert!(serde_json::from_str::<Vec<AccountConfig>>(json).is_err());
+    }
+}
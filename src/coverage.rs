@@ -0,0 +1,95 @@
+use crate::report::Feedback;
+use chrono::{DateTime, NaiveDate};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default)]
+/// Days covered by the report periods of each reporter
+pub struct Coverage {
+    reporters: BTreeMap<String, BTreeSet<NaiveDate>>,
+    begin: Option<i64>,
+    end: Option<i64>,
+}
+
+impl Coverage {
+    pub fn add(&mut self, report: &Feedback) {
+        let range = &report.report_metadata.date_range;
+        self.begin = Some(
+            self.begin
+                .map_or(range.begin, |begin| begin.min(range.begin)),
+        );
+        self.end = Some(self.end.map_or(range.end, |end| end.max(range.end)));
+
+        let days = self
+            .reporters
+            .entry(report.report_metadata.org_name.clone())
+            .or_default();
+        days.extend(days_between(range.begin, range.end));
+    }
+
+    pub fn print(&self) {
+        // Prints the overall period and the days within it without a report, per reporter
+
+        let (begin, end) = match (self.begin, self.end) {
+            (Some(begin), Some(end)) => (begin, end),
+            _ => {
+                println!("No reports to determine the coverage.");
+                return;
+            }
+        };
+        println!(
+            "Reports cover {} - {}",
+            crate::format_timestamp(begin),
+            crate::format_timestamp(end)
+        );
+
+        let all_days = days_between(begin, end);
+        for (reporter, days) in &self.reporters {
+            let missing: Vec<NaiveDate> = all_days
+                .iter()
+                .filter(|day| !days.contains(day))
+                .copied()
+                .collect();
+            if missing.is_empty() {
+                println!("{}: complete", reporter);
+            } else {
+                println!("{}: no reports for {}", reporter, format_ranges(&missing));
+            }
+        }
+    }
+}
+
+fn days_between(begin: i64, end: i64) -> Vec<NaiveDate> {
+    // All days touched by the period, the end is usually the last second of a day
+
+    let (first, last) = match (
+        DateTime::from_timestamp(begin, 0),
+        DateTime::from_timestamp(end, 0),
+    ) {
+        (Some(first), Some(last)) => (first.date_naive(), last.date_naive()),
+        _ => return Vec::new(),
+    };
+    first.iter_days().take_while(|day| *day <= last).collect()
+}
+
+fn format_ranges(days: &[NaiveDate]) -> String {
+    // Joins consecutive days, e.g. "2024-01-01 - 2024-01-03, 2024-01-05"
+
+    let mut ranges: Vec<(NaiveDate, NaiveDate)> = Vec::new();
+    for day in days {
+        match ranges.last_mut() {
+            Some((_, last)) if last.succ_opt() == Some(*day) => *last = *day,
+            _ => ranges.push((*day, *day)),
+        }
+    }
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{} - {}", first, last)
+            }
+        })
+        .collect();
+    ranges.join(", ")
+}
@@ -0,0 +1,323 @@
+use crate::geoip::Database;
+use crate::report::{Feedback, SourceInfo};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+/// Lookups of one IP, stored in the enrichment cache
+struct CacheEntry {
+    #[serde(flatten)]
+    info: SourceInfo,
+    /// Unix timestamps of the lookups, each one is only done if requested
+    #[serde(default)]
+    ptr_checked: Option<i64>,
+    #[serde(default)]
+    geoip_checked: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+/// Adds the host name, country and AS of the source IPs to the records
+///
+/// Disabled unless --resolve-ptr or --geoip-db is given
+pub struct Enrichment {
+    resolve_ptr: bool,
+    databases: Vec<Database>,
+    /// Lookups keyed by IP, loaded from and saved to the cache file if given
+    cache: BTreeMap<String, CacheEntry>,
+    cache_path: Option<PathBuf>,
+    /// Seconds after which a lookup is done again
+    ttl: i64,
+    /// Number of threads for the host name lookups
+    concurrency: usize,
+}
+
+impl Enrichment {
+    pub fn new(
+        resolve_ptr: bool,
+        geoip_dbs: &[PathBuf],
+        cache_path: Option<&Path>,
+        ttl_days: u32,
+        concurrency: usize,
+    ) -> Result<Self> {
+        let databases = geoip_dbs
+            .iter()
+            .map(|path| Database::open(path))
+            .collect::<Result<Vec<_>>>()?;
+        let cache = match cache_path {
+            Some(path) if path.exists() => serde_json::from_reader(File::open(path)?)?,
+            _ => BTreeMap::new(),
+        };
+
+        Ok(Enrichment {
+            resolve_ptr,
+            databases,
+            cache,
+            cache_path: cache_path.map(Path::to_path_buf),
+            ttl: ttl_days as i64 * 86400,
+            concurrency: concurrency.max(1),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.resolve_ptr || !self.databases.is_empty()
+    }
+
+    pub fn enrich(&mut self, report: &mut Feedback) {
+        // Looks up the IPs that are not cached or whose lookup expired, then annotates the records
+
+        if !self.is_enabled() {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp();
+        let ips: BTreeSet<IpAddr> = report
+            .records
+            .iter()
+            .filter_map(|record| record.row.source_ip.trim().parse().ok())
+            .collect();
+
+        if self.resolve_ptr {
+            let missing: Vec<IpAddr> = ips
+                .iter()
+                .copied()
+                .filter(|ip| !self.is_fresh(ip, |entry| entry.ptr_checked, now))
+                .collect();
+            let hosts = resolve_all(&missing, self.concurrency);
+            for (ip, host) in missing.iter().zip(hosts) {
+                let entry = self.cache.entry(ip.to_string()).or_default();
+                entry.info.host = host;
+                entry.ptr_checked = Some(now);
+            }
+        }
+        if !self.databases.is_empty() {
+            for ip in &ips {
+                if self.is_fresh(ip, |entry| entry.geoip_checked, now) {
+                    continue;
+                }
+                let mut info = SourceInfo::default();
+                for database in &self.databases {
+                    if let Err(e) = database.lookup(*ip, &mut info) {
+                        eprintln!("Could not look up {} in the GeoIP database: {}", ip, e);
+                    }
+                }
+                let entry = self.cache.entry(ip.to_string()).or_default();
+                entry.info.country = info.country;
+                entry.info.asn = info.asn;
+                entry.info.as_org = info.as_org;
+                entry.geoip_checked = Some(now);
+            }
+        }
+
+        for record in &mut report.records {
+            let ip: Option<IpAddr> = record.row.source_ip.trim().parse().ok();
+            let entry = match ip.and_then(|ip| self.cache.get(&ip.to_string())) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // The cache may hold lookups of earlier runs that were not requested in this one
+            let mut info = SourceInfo::default();
+            if self.resolve_ptr {
+                info.host = entry.info.host.clone();
+            }
+            if !self.databases.is_empty() {
+                info.country = entry.info.country.clone();
+                info.asn = entry.info.asn;
+                info.as_org = entry.info.as_org.clone();
+            }
+            if info != SourceInfo::default() {
+                record.row.source = Some(info);
+            }
+        }
+    }
+
+    fn is_fresh(&self, ip: &IpAddr, checked: fn(&CacheEntry) -> Option<i64>, now: i64) -> bool {
+        self.cache
+            .get(&ip.to_string())
+            .and_then(checked)
+            .is_some_and(|checked| now - checked < self.ttl)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(path) = &self.cache_path {
+            serde_json::to_writer_pretty(File::create(path)?, &self.cache)?;
+        }
+        Ok(())
+    }
+}
+
+fn resolve_all(ips: &[IpAddr], concurrency: usize) -> Vec<Option<String>> {
+    // Splits the IPs among the threads, the host names are returned in the same order
+
+    if ips.is_empty() {
+        return Vec::new();
+    }
+    let chunk_size = ips.len().div_ceil(concurrency);
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = ips
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let thread =
+                    scope.spawn(move || chunk.iter().map(|ip| reverse_lookup(*ip)).collect());
+                (chunk.len(), thread)
+            })
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|(len, thread)| thread.join().unwrap_or_else(|_| vec![None; len]))
+            .collect()
+    })
+}
+
+#[cfg(unix)]
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    // The resolver of the system, so /etc/hosts and the configured name servers are used
+
+    use std::mem::{size_of, zeroed};
+    use std::os::raw::c_char;
+
+    // NI_MAXHOST of glibc and the BSDs
+    let mut host = [0 as c_char; 1025];
+    let result = unsafe {
+        match ip {
+            IpAddr::V4(ip) => {
+                let mut addr: libc::sockaddr_in = zeroed();
+                addr.sin_family = libc::AF_INET as libc::sa_family_t;
+                addr.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+                #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+                {
+                    addr.sin_len = size_of::<libc::sockaddr_in>() as u8;
+                }
+                libc::getnameinfo(
+                    &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    host.len() as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+            IpAddr::V6(ip) => {
+                let mut addr: libc::sockaddr_in6 = zeroed();
+                addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                addr.sin6_addr.s6_addr = ip.octets();
+                #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+                {
+                    addr.sin6_len = size_of::<libc::sockaddr_in6>() as u8;
+                }
+                libc::getnameinfo(
+                    &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    host.len() as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+    };
+    if result != 0 {
+        return None;
+    }
+    let host = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    Some(host.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn reverse_lookup(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::parse_report;
+
+    const REPORT: &[u8] = include_bytes!("../tests/fixtures/report.xml");
+
+    fn geoip_only(cache: BTreeMap<String, CacheEntry>) -> Enrichment {
+        let databases = ["country.mmdb", "asn.mmdb"]
+            .iter()
+            .map(|name| Database::open(&Path::new("tests/fixtures").join(name)).unwrap())
+            .collect();
+        Enrichment {
+            databases,
+            cache,
+            ttl: 86400,
+            concurrency: 1,
+            ..Enrichment::default()
+        }
+    }
+
+    fn report_from(ip: &str) -> Feedback {
+        let mut report = parse_report(REPORT).unwrap();
+        for record in &mut report.records {
+            record.row.source_ip = ip.to_string();
+        }
+        report
+    }
+
+    #[test]
+    fn combines_databases() {
+        let mut enrichment = geoip_only(BTreeMap::new());
+        let mut report = report_from("192.0.2.1");
+        enrichment.enrich(&mut report);
+        let source = report.records[0].row.source.as_ref().unwrap();
+        assert_eq!(source.country.as_deref(), Some("DE"));
+        assert_eq!(source.asn, Some(64496));
+        assert_eq!(source.host, None);
+        assert!(enrichment.cache["192.0.2.1"].geoip_checked.is_some());
+    }
+
+    #[test]
+    fn uses_fresh_cache_entries() {
+        let mut cache = BTreeMap::new();
+        let cached = CacheEntry {
+            info: SourceInfo {
+                country: Some(String::from("FR")),
+                ..SourceInfo::default()
+            },
+            geoip_checked: Some(chrono::Utc::now().timestamp()),
+            ..CacheEntry::default()
+        };
+        cache.insert(String::from("192.0.2.1"), cached);
+        let mut enrichment = geoip_only(cache);
+        let mut report = report_from("192.0.2.1");
+        enrichment.enrich(&mut report);
+        let source = report.records[0].row.source.as_ref().unwrap();
+        assert_eq!(source.country.as_deref(), Some("FR"));
+    }
+
+    #[test]
+    fn looks_up_expired_cache_entries_again() {
+        let mut cache = BTreeMap::new();
+        let cached = CacheEntry {
+            info: SourceInfo {
+                country: Some(String::from("FR")),
+                ..SourceInfo::default()
+            },
+            geoip_checked: Some(chrono::Utc::now().timestamp() - 2 * 86400),
+            ..CacheEntry::default()
+        };
+        cache.insert(String::from("192.0.2.1"), cached);
+        let mut enrichment = geoip_only(cache);
+        let mut report = report_from("192.0.2.1");
+        enrichment.enrich(&mut report);
+        let source = report.records[0].row.source.as_ref().unwrap();
+        assert_eq!(source.country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn unknown_ips_are_not_annotated() {
+        let mut enrichment = geoip_only(BTreeMap::new());
+        let mut report = report_from("198.51.100.1");
+        enrichment.enrich(&mut report);
+        assert!(report.records[0].row.source.is_none());
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(!json.contains("\"source\""));
+    }
+}
@@ -0,0 +1,37 @@
+use mailparse::MailParseError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+/// Reasons why no report could be extracted from a message, or from the server at all
+pub enum ExtractError {
+    #[error("No attachment found.")]
+    NoAttachment,
+    #[error("No file name found.")]
+    NoFilename,
+    #[error("Unsupported mimetype {0}.")]
+    UnsupportedMime(String),
+    #[error("Could not decompress attachment: {0}")]
+    DecompressionFailed(String),
+    #[error("Could not decode body: {0}")]
+    BodyDecodeFailed(#[from] MailParseError),
+    #[error("Could not get the capabilities of the server: {0}")]
+    CapabilitiesFailed(String),
+}
+
+impl ExtractError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExtractError::NoAttachment => "no-attachment",
+            ExtractError::NoFilename => "no-filename",
+            ExtractError::UnsupportedMime(_) => "unsupported-mimetype",
+            ExtractError::DecompressionFailed(_) => "decompress",
+            ExtractError::BodyDecodeFailed(_) => "decode",
+            ExtractError::CapabilitiesFailed(_) => "capabilities",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+/// The server rejected the credentials, retrying would only risk getting the account locked
+#[error("The server rejected the login, check the account and password: {0}")]
+pub struct LoginRejected(pub String);
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+/// A message that could not be processed
+pub struct FailedMessage {
+    /// Selected folder name, including the namespace prefix
+    pub folder: String,
+    pub uid: u32,
+    /// UIDVALIDITY of the mailbox when the failure was recorded
+    pub uid_validity: Option<u32>,
+    pub message_id: Option<String>,
+    /// Short category of the failure, e.g. "fetch" or "decompress"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    pub reason: String,
+    /// Mimetypes of the parts of the message, to see what the reporter sent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mimetypes: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+/// Messages that failed, kept across runs to retry them with --redownload-failed
+pub struct ErrorLog {
+    entries: Vec<FailedMessage>,
+}
+
+impl From<Vec<FailedMessage>> for ErrorLog {
+    fn from(entries: Vec<FailedMessage>) -> Self {
+        ErrorLog { entries }
+    }
+}
+
+impl ErrorLog {
+    pub fn load(path: &Path) -> Result<ErrorLog> {
+        // Loads an existing error log, a missing file results in an empty one
+
+        if !path.exists() {
+            return Ok(ErrorLog::default());
+        }
+        let file = File::open(path)?;
+        Ok(ErrorLog {
+            entries: serde_json::from_reader(file)?,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &self.entries)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn add(&mut self, entry: FailedMessage) {
+        // Replaces an older entry for the same message
+
+        self.remove(&entry.folder, entry.uid);
+        self.entries.push(entry);
+    }
+
+    pub fn remove(&mut self, folder: &str, uid: u32) {
+        self.entries.retain(|e| e.folder != folder || e.uid != uid);
+    }
+
+    pub fn folders(&self) -> Vec<String> {
+        let mut folders: Vec<String> = self.entries.iter().map(|e| e.folder.clone()).collect();
+        folders.sort();
+        folders.dedup();
+        folders
+    }
+
+    pub fn uids(&mut self, folder: &str, uid_validity: Option<u32>) -> Vec<u32> {
+        // Returns the UIDs of the failed messages of the folder. Entries recorded with a different
+        // UIDVALIDITY refer to other messages by now and are dropped.
+
+        let before = self.entries.len();
+        self.entries.retain(|e| {
+            e.folder != folder
+                || e.uid_validity.is_none()
+                || uid_validity.is_none()
+                || e.uid_validity == uid_validity
+        });
+        if self.entries.len() < before {
+            eprintln!(
+                "Warning: UIDVALIDITY of folder '{}' changed, dropping {} entries of the error log.",
+                folder,
+                before - self.entries.len()
+            );
+        }
+
+        let mut uids: Vec<u32> = self
+            .entries
+            .iter()
+            .filter(|e| e.folder == folder)
+            .map(|e| e.uid)
+            .collect();
+        uids.sort_unstable();
+        uids
+    }
+}
@@ -0,0 +1,110 @@
+use crate::report::SourceInfo;
+use anyhow::{anyhow, Result};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::net::IpAddr;
+use std::path::Path;
+
+/// A MaxMind DB file, e.g. GeoLite2-Country.mmdb or GeoLite2-ASN.mmdb
+pub struct Database {
+    reader: Reader<Vec<u8>>,
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader =
+            Reader::open_readfile(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(Database { reader })
+    }
+
+    /// Adds the country and autonomous system of the IP, as far as the database knows them
+    pub fn lookup(&self, ip: IpAddr, info: &mut SourceInfo) -> Result<()> {
+        // Country databases have the country of the network, ASN databases the AS. Each lookup
+        // results in empty fields for the other kind of database.
+
+        let country: geoip2::Country = match found(self.reader.lookup(ip))? {
+            Some(country) => country,
+            None => return Ok(()),
+        };
+        let iso_code = country
+            .country
+            .and_then(|country| country.iso_code)
+            .or(country
+                .registered_country
+                .and_then(|country| country.iso_code));
+        if let Some(iso_code) = iso_code {
+            info.country = Some(iso_code.to_string());
+        }
+
+        if let Some(asn) = found::<geoip2::Asn>(self.reader.lookup(ip))? {
+            if let Some(number) = asn.autonomous_system_number {
+                info.asn = Some(number);
+            }
+            if let Some(org) = asn.autonomous_system_organization {
+                info.as_org = Some(org.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn found<T>(result: Result<T, MaxMindDBError>) -> Result<Option<T>> {
+    // IPs of networks that are not in the database are not an error
+
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+        Err(e) => Err(anyhow!("{}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(name: &str) -> Database {
+        Database::open(&Path::new("tests/fixtures").join(name)).unwrap()
+    }
+
+    #[test]
+    fn finds_country_of_ipv4_network() {
+        let mut info = SourceInfo::default();
+        open("country.mmdb")
+            .lookup("192.0.2.17".parse().unwrap(), &mut info)
+            .unwrap();
+        assert_eq!(info.country.as_deref(), Some("DE"));
+        assert_eq!(info.asn, None);
+    }
+
+    #[test]
+    fn finds_ipv4_in_ipv6_database() {
+        let mut info = SourceInfo::default();
+        open("asn.mmdb")
+            .lookup("192.0.2.200".parse().unwrap(), &mut info)
+            .unwrap();
+        assert_eq!(info.asn, Some(64496));
+        assert_eq!(info.as_org.as_deref(), Some("Example AS"));
+    }
+
+    #[test]
+    fn unknown_networks_add_nothing() {
+        let database = open("country.mmdb");
+        let mut info = SourceInfo::default();
+        for ip in ["198.51.100.1", "2001:db8::1"].iter() {
+            database.lookup(ip.parse().unwrap(), &mut info).unwrap();
+        }
+        assert_eq!(info, SourceInfo::default());
+    }
+
+    #[test]
+    fn rejects_file_without_metadata() {
+        assert!(Database::open(Path::new("tests/fixtures/report.xml")).is_err());
+    }
+}
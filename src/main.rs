@@ -1,16 +1,63 @@
 use anyhow::{anyhow, Result};
+use imap_proto::types::{BodyStructure, MessageSection, SectionPath};
 use libflate::gzip::Decoder;
+use mailparse::body::Body;
 use mailparse::*;
-use native_tls::TlsConnector;
+use native_tls::{Identity, TlsConnector};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use zip::ZipArchive;
 extern crate libflate;
 extern crate rpassword;
 
-#[derive(Debug, StructOpt)]
+mod accounts;
+mod coverage;
+mod enrichment;
+mod error;
+mod errorlog;
+mod geoip;
+mod manifest;
+#[cfg(feature = "serve")]
+mod metrics;
+mod report;
+mod reporters;
+#[cfg(feature = "validate-schema")]
+mod schema;
+mod sink;
+mod sources;
+mod state;
+mod stats;
+mod stream;
+mod tlsrpt;
+
+use accounts::AccountConfig;
+use coverage::Coverage;
+use enrichment::Enrichment;
+use error::{ExtractError, LoginRejected};
+use errorlog::{ErrorLog, FailedMessage};
+use manifest::{Manifest, ManifestEntry};
+use report::{parse_report, strip_bom, Feedback};
+use reporters::Reporters;
+use sink::{FileSink, ReportSink, StdoutSink};
+use sources::Sources;
+use state::{FolderState, KnownReport, State};
+use stats::Statistics;
+use stream::{Connector, SharedStream};
+use tlsrpt::parse_tlsrpt;
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(setting = AppSettings::SubcommandsNegateReqs)]
 /// imap_dmarc_extractor
 ///
 /// Will connect to an IMAP server and try to extract all DMARC reports,
@@ -18,22 +65,588 @@ extern crate rpassword;
 struct Opt {
     /// IMAP Server
     /// mail.example.com:993
-    #[structopt()]
-    server: String,
+    #[structopt(env = "IMAP_SERVER", required_unless = "accounts")]
+    server: Option<String>,
 
     /// Username for the IMAP account
-    #[structopt()]
-    account: String,
+    #[structopt(env = "IMAP_ACCOUNT", required_unless = "accounts")]
+    account: Option<String>,
 
     /// Password for the IMAP account
     #[structopt(short, long)]
     password: Option<String>,
 
+    /// Read the password from this file descriptor, e.g. passed by the service manager. The
+    /// descriptor can only be read once, so it can't be used for the accounts of --accounts.
+    #[cfg(unix)]
+    #[structopt(long, conflicts_with_all = &["password", "accounts"])]
+    password_fd: Option<i32>,
+
+    /// Read the password from the keyring of the OS, stored for this service and the account
+    #[cfg(feature = "keyring")]
+    #[structopt(long, conflicts_with = "password")]
+    keyring: Option<String>,
+
+    /// Prompt for the password and store it in the keyring for future runs
+    #[cfg(feature = "keyring")]
+    #[structopt(long, requires = "keyring")]
+    store_password: bool,
+
     /// Path where to store the reports
+    #[structopt(parse(from_os_str), env = "IMAP_PATH", required_unless = "accounts")]
+    path: Option<PathBuf>,
+
+    /// JSON file with several accounts to process one after the other, instead of the server,
+    /// account and path arguments
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "password",
+            "state",
+            "error-log",
+            "redownload-failed",
+            "replay-dir",
+            "output-stdout",
+        ]
+    )]
+    accounts: Option<PathBuf>,
+
+    /// With --accounts, stop at the first account that fails instead of continuing with the next
+    #[structopt(long, requires = "accounts")]
+    fail_fast: bool,
+
+    /// Directory for the temporary files reports are written to before moving them into place
+    /// Defaults to the output path, should be on the same file system
+    #[structopt(long, parse(from_os_str))]
+    temp_dir: Option<PathBuf>,
+
+    /// Path where to store DMARC aggregate reports, defaults to the output path
+    #[structopt(long, parse(from_os_str))]
+    dmarc_path: Option<PathBuf>,
+
+    /// Path where to store SMTP TLS reports, defaults to the output path
+    #[structopt(long, parse(from_os_str))]
+    tlsrpt_path: Option<PathBuf>,
+
+    /// Path where to store DMARC failure reports as .eml files, defaults to the output path
+    #[structopt(long, parse(from_os_str))]
+    forensic_path: Option<PathBuf>,
+
+    /// Folder containing the reports, defaults to INBOX
+    /// Can be given multiple times, all folders are processed in one session. The personal
+    /// namespace prefix of the server is added if needed
+    #[structopt(long = "folder", number_of_values = 1)]
+    folders: Vec<String>,
+
+    /// Use the first folder with "dmarc" in its name, or the INBOX if there is none
+    #[structopt(long, conflicts_with = "folders")]
+    auto_folder: bool,
+
+    /// State file to remember already processed messages
+    /// Only messages that arrived since the last run will be fetched
+    #[structopt(long, parse(from_os_str))]
+    state: Option<PathBuf>,
+
+    /// Only process messages with this UID or higher, instead of the ones after the state file
+    #[structopt(long, conflicts_with = "redownload-failed")]
+    since_uid: Option<u32>,
+
+    /// Only search for messages received since the day of the last run without errors
+    /// Needs no per-message state, but messages of that day are processed again
+    #[structopt(long, requires = "state")]
+    incremental: bool,
+
+    /// Only search for messages received today or in the n days before, e.g. 7 for the last week
+    /// up to today
+    #[structopt(long, conflicts_with = "incremental")]
+    since_days: Option<u32>,
+
+    /// Read-only sweep over all messages of the folders
+    /// Messages are not marked as seen and no state is read or written
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "state",
+            "since-uid",
+            "only-unseen",
+            "error-log",
+            "redownload-failed",
+        ]
+    )]
+    all: bool,
+
+    /// Skip messages that are already marked as seen
+    #[structopt(long)]
+    only_unseen: bool,
+
+    /// Client certificate for TLS client authentication
+    /// PKCS#12 archive, or PEM certificate if --client-key is given
+    #[structopt(long, parse(from_os_str))]
+    client_cert: Option<PathBuf>,
+
+    /// PEM encoded PKCS#8 private key for the client certificate
+    #[structopt(long, parse(from_os_str), requires = "client-cert")]
+    client_key: Option<PathBuf>,
+
+    /// Accept a server certificate that expired at most this many days ago
+    /// Everything else about the certificate is still verified
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    #[structopt(long, conflicts_with = "client-cert")]
+    tls_expired_grace: Option<u32>,
+
+    /// Only process messages with a Date newer than the newest one of the last run
+    /// Uses the state file instead of tracking UIDs
+    #[structopt(long, requires = "state")]
+    only_new_since_last_run: bool,
+
+    /// Only store reports for this domain
+    /// Can be given multiple times
+    #[structopt(long = "domain", number_of_values = 1)]
+    domains: Vec<String>,
+
+    /// Only store the report with this report id, e.g. to fetch a report referenced elsewhere
+    /// Fails if it is not found. The report has to be parsed, so it can't be kept compressed
+    #[structopt(long, conflicts_with = "no-decompress")]
+    report_id: Option<String>,
+
+    /// Compress the connection if the server supports COMPRESS=DEFLATE
+    #[structopt(long)]
+    compress: bool,
+
+    /// Only fetch the headers and the part containing the report, not the whole message
+    /// Needs an additional request per message, saves bandwidth for messages with big other parts
+    #[structopt(long)]
+    partial_fetch: bool,
+
+    /// Stop after this many seconds, finishing the current message, and exit with code 2
+    /// The state file is saved, so the next run continues with the remaining messages
+    #[structopt(long, conflicts_with = "replay-dir")]
+    max_runtime: Option<u64>,
+
+    /// Keep running and process the folders again after waiting this many seconds
+    /// Each run starts where the last one ended if a state file is given
+    #[structopt(
+        long,
+        conflicts_with_all = &["replay-dir", "preflight", "output-stdout", "count-only"]
+    )]
+    watch: Option<u64>,
+
+    /// Number of messages to fetch at once
+    #[structopt(long, default_value = "100")]
+    batch_size: usize,
+
+    /// JSON file listing all written reports
+    /// Entries are added to an existing file, replacing older ones for the same report
+    #[structopt(long, parse(from_os_str))]
+    manifest: Option<PathBuf>,
+
+    /// JSON file listing the messages that could not be processed, with the reason
+    /// Entries are removed once the message is processed successfully
+    #[structopt(long, parse(from_os_str))]
+    error_log: Option<PathBuf>,
+
+    /// JSON file listing every failure of this run, with the mimetypes of the message parts
+    /// Written at the end of the run, in the format of --error-log
+    #[structopt(long, parse(from_os_str))]
+    errors_file: Option<PathBuf>,
+
+    /// Process the raw messages stored in this directory instead of connecting to the server
+    /// Server and account are not used
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["state", "error-log", "redownload-failed", "count-only"]
+    )]
+    replay_dir: Option<PathBuf>,
+
+    /// Store the raw messages in this directory, to process them again later with --replay-dir
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["replay-dir", "partial-fetch"]
+    )]
+    save_eml: Option<PathBuf>,
+
+    /// Only process the messages listed in this error log and update it
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["error-log", "folders", "auto-folder", "incremental", "count-only"]
+    )]
+    redownload_failed: Option<PathBuf>,
+
+    /// Print the MIME structure of every message
+    #[structopt(long)]
+    trace_mime: bool,
+
+    /// Store the attachments as they are, without decompressing them
+    #[structopt(long, conflicts_with = "domains")]
+    no_decompress: bool,
+
+    /// How the attachments are stored with --no-decompress, "none" or "gzip"
+    /// "none" keeps them as received, "gzip" stores every report in them compressed with gzip
+    #[structopt(
+        long,
+        default_value = "none",
+        possible_values = &["none", "gzip"],
+        requires_if("gzip", "no-decompress")
+    )]
+    recompress: String,
+
+    /// Compression level from 0 to 9, for --recompress gzip and --gzip-output
+    #[structopt(
+        long,
+        default_value = "6",
+        possible_values = &["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]
+    )]
+    compression_level: u32,
+
+    /// Remove a UTF-8 byte order mark at the start of the reports
+    #[structopt(long, conflicts_with = "no-decompress")]
+    strip_bom: bool,
+
+    /// Store every report in a subdirectory of the output path named after its domain
+    /// Reports that can't be parsed are stored in "_unknown"
+    #[structopt(long, conflicts_with_all = &["no-decompress", "output-stdout"])]
+    split_by_domain: bool,
+
+    /// Store the DMARC reports of the run in one JSON file per domain, e.g. example.com.json
+    /// Instead of a file per report. Reports that can't be parsed are skipped
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "no-decompress",
+            "output-stdout",
+            "json-stream",
+            "split-by-domain",
+            "summary-only",
+            "gzip-output",
+            "manifest",
+        ]
+    )]
+    merge_by_domain: bool,
+
+    /// Store the reports in a canonical form, with sorted records and fixed indentation
+    /// Only the fields known to the tool are kept
+    #[structopt(long, conflicts_with = "no-decompress")]
+    normalize_output: bool,
+
+    /// How reports with the same id are compared, "bytes" or "normalized"
+    /// "normalized" compares the canonical form of the reports, see --normalize-output
+    #[structopt(long, default_value = "bytes", possible_values = &["bytes", "normalized"])]
+    dedup_mode: String,
+
+    /// Skip reports that were already extracted in the last n days, across runs
+    /// The reports are remembered in the state file and forgotten after the window
+    #[structopt(long)]
+    dedupe_window: Option<u32>,
+
+    /// Which attachments to extract if a message has several, "all", "first" or "largest"
+    /// "largest" compares the decompressed size, e.g. for reporters that attach a zip and a gzip
+    #[structopt(
+        long,
+        default_value = "all",
+        possible_values = &["all", "first", "largest"]
+    )]
+    multi_attachment: String,
+
+    /// Compress every extracted report with gzip and store it as <name>.gz
+    #[structopt(long, conflicts_with = "no-decompress")]
+    gzip_output: bool,
+
+    /// Only print pass/fail statistics per domain, no report files are written
+    #[structopt(long, conflicts_with_all = &["no-decompress", "gzip-output", "manifest"])]
+    summary_only: bool,
+
+    /// Check every report for required fields and allowed values of the aggregate report format
+    /// This is not a validation against the XSD. Violations are printed, the reports are still
+    /// stored unless --strict is given
+    #[structopt(long, conflicts_with = "no-decompress")]
+    check_rules: bool,
+
+    /// Validate every report against the XSD of the aggregate report format (RFC 7489)
+    /// Violations are printed, the reports are still stored unless --strict is given
+    #[cfg(feature = "validate-schema")]
+    #[structopt(long, conflicts_with = "no-decompress")]
+    validate_schema: bool,
+
+    /// Skip reports that can't be parsed or violate --check-rules or --validate-schema
+    #[structopt(long)]
+    strict: bool,
+
+    /// Write reports that violate --check-rules or --validate-schema to this directory instead of the output path
+    /// With --strict also the ones that can't be parsed. A .error file next to them says why.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["output-stdout", "json-stream", "diff"]
+    )]
+    quarantine_dir: Option<PathBuf>,
+
+    /// Print the period covered by the reports and the days without a report, per reporter
+    #[structopt(long, conflicts_with_all = &["no-decompress", "output-stdout"])]
+    coverage: bool,
+
+    /// Print the reporting organizations with the number of reports and their most recent one
+    #[structopt(long, conflicts_with_all = &["no-decompress", "output-stdout", "json-stream"])]
+    reporters: bool,
+
+    /// Print the n source IPs with the most messages that failed DMARC or were not delivered
+    #[structopt(long, conflicts_with_all = &["no-decompress", "output-stdout", "json-stream"])]
+    top_sources: Option<usize>,
+
+    /// Write the report to stdout instead of the output path, all other output goes to stderr
+    /// Fails if more than one report is found, e.g. use together with --limit 1
+    #[structopt(long, conflicts_with_all = &["summary-only", "manifest", "gzip-output"])]
+    output_stdout: bool,
+
+    /// Print the summary at the end of the run aligned and with colors
+    /// Colors are left out if the output is not a terminal or NO_COLOR is set
+    #[structopt(long)]
+    pretty_summary: bool,
+
+    /// Don't print the percentage of processed messages
+    #[structopt(long)]
+    no_progress: bool,
+
+    /// Print the percentage of processed messages even if the output is not a terminal
+    #[structopt(long, conflicts_with = "no-progress")]
+    force_progress: bool,
+
+    /// Don't print the summary at the end of the run
+    #[structopt(long, conflicts_with = "pretty-summary")]
+    quiet: bool,
+
+    /// Warn about reports whose period ended more than this many days ago
+    #[structopt(long, conflicts_with = "no-decompress")]
+    warn_older_than: Option<u32>,
+
+    /// Write every DMARC report as one line of JSON to stdout instead of storing it
+    /// All other output goes to stderr, SMTP TLS reports are skipped
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "no-decompress", "output-stdout", "summary-only", "gzip-output", "split-by-domain", "manifest"
+        ]
+    )]
+    json_stream: bool,
+
+    /// Only keep the records with this disposition for --json-stream and --merge-by-domain
+    /// Can be given several times, reports without such records are skipped
+    #[structopt(
+        long = "disposition",
+        number_of_values = 1,
+        possible_values = &["none", "quarantine", "reject"]
+    )]
+    dispositions: Vec<String>,
+
+    /// Look up the host names of the source IPs for --json-stream and --merge-by-domain
+    #[cfg(unix)]
+    #[structopt(long)]
+    resolve_ptr: bool,
+
+    /// MaxMind DB file to look up the country and AS of the source IPs, e.g. GeoLite2-ASN.mmdb
+    /// For --json-stream and --merge-by-domain, can be given several times
+    #[structopt(long = "geoip-db", number_of_values = 1, parse(from_os_str))]
+    geoip_dbs: Vec<PathBuf>,
+
+    /// JSON file to keep the lookups of --resolve-ptr and --geoip-db between runs
+    #[structopt(long, parse(from_os_str))]
+    enrichment_cache: Option<PathBuf>,
+
+    /// Days after which a lookup in the --enrichment-cache is done again
+    #[structopt(long, default_value = "7")]
+    enrichment_ttl: u32,
+
+    /// Number of host name lookups running at the same time
+    #[structopt(long, default_value = "4")]
+    lookup_concurrency: usize,
+
+    /// Skip messages bigger than this many bytes without downloading them
+    #[structopt(long)]
+    max_message_size: Option<u32>,
+
+    /// Process the messages with the newest Date header first
+    #[structopt(long)]
+    newest_first: bool,
+
+    /// Only process the newest n messages of each folder
+    /// Older messages are skipped for good if a state file is used
+    #[structopt(long)]
+    max_messages_per_folder: Option<usize>,
+
+    /// Number of times to reconnect if the connection to the server is lost
+    #[structopt(long, default_value = "3")]
+    max_reconnects: usize,
+
+    /// Seconds to wait for the TCP connection to the server, per connection attempt
+    #[structopt(long, default_value = "15")]
+    connect_timeout: u64,
+
+    /// Process at most this many messages per second, for servers that throttle clients
+    /// Sleeps between messages as needed, there is no allowance for bursts
+    #[structopt(long)]
+    max_messages_per_second: Option<u32>,
+
+    /// Only process this many messages, across all folders
+    #[structopt(long)]
+    limit: Option<usize>,
+
+    /// Exit with an error if no DMARC report was extracted
+    #[structopt(long)]
+    fail_on_no_reports: bool,
+
+    /// Only print how many messages would be processed, nothing is fetched
+    #[structopt(long, conflicts_with_all = &["output-stdout", "fail-on-no-reports"])]
+    count_only: bool,
+
+    /// Print the effective configuration and exit without connecting
+    /// Also printed with --dry-run --verbose
+    #[structopt(long)]
+    preflight: bool,
+
+    /// Only print which files would be written
+    /// Messages are not marked as seen and the state file is not updated
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// With --dry-run, compare each report with an existing file of the same name
+    /// Shows whether the report would be created, updated or is unchanged
+    #[structopt(long, requires = "dry-run", conflicts_with = "output-stdout")]
+    diff: bool,
+
+    /// Print more details, e.g. why a message was skipped
+    #[structopt(short, long)]
+    verbose: bool,
+}
+
+impl Opt {
+    /// Output path, given on the command line or for each account of --accounts
+    fn output_path(&self) -> &Path {
+        self.path.as_deref().expect("output path is set")
+    }
+
+    /// Reports are checked with --check-rules or --validate-schema
+    fn checks_reports(&self) -> bool {
+        #[cfg(feature = "validate-schema")]
+        if self.validate_schema {
+            return true;
+        }
+        self.check_rules
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "show")]
+/// Prints the records of a DMARC report as a table
+///
+/// The report can be a plain XML file or still compressed
+struct Show {
+    /// Report file
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "selfcheck")]
+/// Runs the sample files of a directory through decompression and parsing
+///
+/// The directory contains the subdirectories good and bad. Samples can be report files or mails
+/// as .eml files. Fails if a good sample can't be processed
+struct SelfCheck {
+    /// Directory with the samples
     #[structopt(parse(from_os_str))]
-    path: PathBuf,
+    dir: PathBuf,
+}
+
+#[cfg(feature = "serve")]
+#[derive(Debug, StructOpt)]
+#[structopt(name = "serve")]
+/// Runs with --watch and serves Prometheus metrics of the runs over HTTP
+///
+/// Takes the same arguments as a normal run, the metrics are served at /metrics
+struct Serve {
+    /// Address and port the HTTP server listens on
+    #[structopt(long, default_value = "127.0.0.1:9898")]
+    listen: std::net::SocketAddr,
+
+    #[structopt(flatten)]
+    opt: Opt,
+}
+
+type ImapSession = imap::Session<SharedStream>;
+
+/// Progress output goes to stderr if stdout is used for the report
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if LOG_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, Default)]
+/// Statistics of all processed folders
+struct Summary {
+    messages: usize,
+    reports: usize,
+    tls_reports: usize,
+    /// DMARC failure reports, stored as the whole message
+    forensic_reports: usize,
+    failed: usize,
+    failed_folders: usize,
+    /// Reports older than --warn-older-than
+    old_reports: usize,
+    /// Reports written to --quarantine-dir
+    quarantined: usize,
+    /// Messages left for the next run as --max-runtime was reached
+    unprocessed: usize,
+    deadline_reached: bool,
+    /// A second report was found with --output-stdout, the run stopped
+    too_many_reports: bool,
+    /// Outcome of the comparison with existing files with --diff
+    would_create: usize,
+    would_update: usize,
+    unchanged: usize,
+    statistics: Statistics,
+    coverage: Coverage,
+    reporters: Reporters,
+    sources: Sources,
+    /// Failures of this run for --errors-file
+    errors: Vec<FailedMessage>,
+    /// Reports collected for --merge-by-domain, per domain
+    merged: BTreeMap<String, Vec<Feedback>>,
+    /// First report seen in this run for each report id
+    seen_reports: HashMap<String, SeenReport>,
+    /// Reports extracted within --dedupe-window, taken from and written back to the state
+    known_reports: BTreeMap<String, KnownReport>,
+    /// Reports skipped as they were extracted within --dedupe-window
+    duplicates: usize,
+    /// Lookups of the source IPs for the JSON output
+    enrichment: Enrichment,
 }
 
+#[derive(Debug)]
+struct SeenReport {
+    hash: u64,
+    message_id: String,
+    name: String,
+}
+
+/// Fields of the machine readable part of a DMARC failure report
+struct FailureReport {
+    reported_domain: String,
+    source_ip: String,
+    /// Which mechanisms failed, e.g. dkim, spf or dmarc
+    auth_failure: String,
+}
+
+/// A report as attached to a mail, before and after decompressing it
 struct Attachment {
     content: Vec<u8>,
     decompressed: Option<Vec<u8>>,
@@ -41,141 +654,4664 @@ struct Attachment {
     name: String,
 }
 
-const USABLE_MIMETYPES: [&'static str; 3] = [
+impl Attachment {
+    fn new(content: Vec<u8>, mimetype: &str, name: &str) -> Self {
+        Attachment {
+            content,
+            decompressed: None,
+            mimetype: String::from(mimetype),
+            name: String::from(name),
+        }
+    }
+
+    fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Only set after the attachment was decompressed
+    fn decompressed(&self) -> Option<&[u8]> {
+        self.decompressed.as_deref()
+    }
+
+    fn mimetype(&self) -> &str {
+        &self.mimetype
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+const USABLE_MIMETYPES: [&str; 9] = [
     "application/zip",
     "application/gzip",
     "application/octet-stream",
+    TAR_MIMETYPE,
+    "application/x-gtar",
+    TLSRPT_GZIP_MIMETYPE,
+    TLSRPT_JSON_MIMETYPE,
+    XML_MIMETYPE,
+    "text/xml",
+];
+
+const TLSRPT_GZIP_MIMETYPE: &str = "application/tlsrpt+gzip";
+const TLSRPT_JSON_MIMETYPE: &str = "application/tlsrpt+json";
+
+const XML_MIMETYPE: &str = "application/xml";
+
+const COMPRESSION_EXTENSIONS: [&str; 6] = ["gz", "gzip", "tgz", "tar", "zip", "7z"];
+
+/// How deep messages embedded in a multipart/digest are followed, e.g. a digest in a digest
+const MAX_EMBEDDED_DEPTH: usize = 3;
+
+/// Boundary of the messages put together with --partial-fetch
+const PART_BOUNDARY: &str = "imap_dmarc_extract-part";
+
+/// Directory for reports whose domain is unknown with --split-by-domain
+const UNKNOWN_DOMAIN_DIR: &str = "_unknown";
+
+const SEVEN_Z_MIMETYPE: &str = "application/x-7z-compressed";
+
+const TNEF_MIMETYPE: &str = "application/ms-tnef";
+
+const TAR_MIMETYPE: &str = "application/x-tar";
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    Zip,
+    Gzip,
+    SevenZ,
+    Tar,
+    Plain,
+}
+
+impl Format {
+    fn detect(content: &[u8]) -> Format {
+        // Determines the format by the magic bytes at the start of the content
+
+        if content.starts_with(b"PK\x03\x04") {
+            Format::Zip
+        } else if content.starts_with(&[0x1f, 0x8b]) {
+            Format::Gzip
+        } else if content.starts_with(b"7z\xbc\xaf\x27\x1c") {
+            Format::SevenZ
+        } else if content.get(257..262) == Some(b"ustar") {
+            Format::Tar
+        } else {
+            Format::Plain
+        }
+    }
+
+    fn mimetype(&self) -> Option<&'static str> {
+        match self {
+            Format::Zip => Some("application/zip"),
+            Format::Gzip => Some("application/gzip"),
+            Format::SevenZ => Some(SEVEN_Z_MIMETYPE),
+            Format::Tar => Some(TAR_MIMETYPE),
+            Format::Plain => None,
+        }
+    }
+}
+
+/// Exit code of a run that was stopped by --max-runtime
+const DEADLINE_EXIT_CODE: i32 = 2;
+
+/// Name of the password in the credentials passed by systemd, e.g. with LoadCredential=
+const CREDENTIAL_NAME: &str = "imap-password";
+
+const RELEVANT_CAPABILITIES: [&str; 7] = [
+    "IDLE",
+    "MOVE",
+    "UIDPLUS",
+    "X-GM-EXT-1",
+    "CONDSTORE",
+    "QRESYNC",
+    "SORT",
 ];
 
 fn main() {
-    let opt = Opt::from_args();
+    let app = Opt::clap()
+        .subcommand(Show::clap())
+        .subcommand(SelfCheck::clap());
+    #[cfg(feature = "serve")]
+    let app = app.subcommand(Serve::clap());
+    let matches = app.get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("show") {
+        let show = Show::from_clap(matches);
+        if let Err(e) = show_report(&show.file) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("selfcheck") {
+        let selfcheck = SelfCheck::from_clap(matches);
+        match self_check(&selfcheck.dir) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    #[cfg(feature = "serve")]
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        let serve = Serve::from_clap(matches);
+        if serve.opt.watch.is_none() {
+            eprintln!(
+                "serve needs --watch, the metrics are collected while the tool keeps running"
+            );
+            std::process::exit(1);
+        }
+        if let Err(e) = metrics::serve(serve.listen) {
+            eprintln!("Could not listen on {}: {}", serve.listen, e);
+            std::process::exit(1);
+        }
+        eprintln!("Serving metrics at http://{}/metrics", serve.listen);
+        std::process::exit(start(serve.opt));
+    }
+
+    match start(Opt::from_clap(&matches)) {
+        0 => (),
+        code => std::process::exit(code),
+    }
+}
+
+fn start(mut opt: Opt) -> i32 {
+    // Checks the combinations of options clap can't express, then runs once or with --watch
+    // until the process is stopped. Returns the exit code.
+
+    LOG_TO_STDERR.store(opt.output_stdout || opt.json_stream, Ordering::Relaxed);
+
+    if !opt.dispositions.is_empty() && !opt.json_stream && !opt.merge_by_domain {
+        eprintln!("--disposition only works with --json-stream or --merge-by-domain");
+        return 1;
+    }
+    if (opt.strict || opt.quarantine_dir.is_some()) && !opt.checks_reports() {
+        eprintln!("--strict and --quarantine-dir need --check-rules or --validate-schema");
+        return 1;
+    }
+    if opt.dedupe_window.is_some() && opt.state.is_none() && opt.accounts.is_none() {
+        eprintln!("--dedupe-window needs --state to remember the reports");
+        return 1;
+    }
+
+    let seconds = match opt.watch {
+        Some(seconds) => seconds,
+        None => return run_once(&opt),
+    };
+    if opt.accounts.is_none() {
+        // A prompt in each run would block the loop, and a file descriptor can only be read once
+        match get_password(&opt) {
+            Ok(password) => opt.password = Some(password),
+            Err(e) => {
+                eprintln!("Could not get password: {}", e);
+                return 1;
+            }
+        }
+    }
+    loop {
+        #[cfg(feature = "serve")]
+        let started = chrono::Utc::now().timestamp();
+        let code = run_once(&opt);
+        #[cfg(feature = "serve")]
+        metrics::update(|metrics| {
+            metrics.runs += 1;
+            if code == 0 {
+                metrics.last_success = Some(started);
+            } else if code != DEADLINE_EXIT_CODE {
+                metrics.failed_runs += 1;
+            }
+        });
+        if code != 0 && code != DEADLINE_EXIT_CODE {
+            eprintln!("The run failed, trying again in {} seconds", seconds);
+        } else if !opt.quiet {
+            log!("Next run in {} seconds", seconds);
+        }
+        std::thread::sleep(Duration::from_secs(seconds));
+    }
+}
+
+fn run_once(opt: &Opt) -> i32 {
+    if let Some(accounts) = &opt.accounts {
+        return run_batch(opt, accounts);
+    }
+    match run(opt, None) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
 
-    let v: Vec<&str> = opt.server.split(':').collect();
-    let account = opt.account;
-    let path = opt.path;
+fn run(opt: &Opt, idle: Option<&mut Option<IdleConnection>>) -> Result<i32> {
+    // Processes the folders of one account and returns the exit code. Connection and login
+    // failures are returned as errors. With an idle connection given, a connection to the same
+    // server is reused, and the connection is kept for the next account if the server allows.
+
+    let v: Vec<&str> = opt
+        .server
+        .as_deref()
+        .unwrap_or_default()
+        .split(':')
+        .collect();
+    let account = opt.account.as_deref().unwrap_or_default();
     let server = v[0];
     let mut port = 993;
 
     if v.len() > 1 {
-        port = v[1].parse().unwrap();
+        port = v[1]
+            .parse()
+            .map_err(|_| anyhow!("Invalid port in server {}", v.join(":")))?;
     }
 
-    let password = rpassword::prompt_password_stdout("Password: ").unwrap();
+    if let Some(temp_dir) = &opt.temp_dir {
+        check_temp_dir(opt.output_path(), temp_dir);
+    }
 
-    println!(
-        "Will connect to {} on port {} with account '{}'",
-        server, port, account
-    );
-    let tls = TlsConnector::builder().build().unwrap();
-    let client = imap::connect((server, port), server, &tls).expect("Error connecting to server");
-    let mut imap_session = client.login(account, password).unwrap();
+    if opt.preflight || (opt.dry_run && opt.verbose) {
+        print_config(opt, server, port);
+    }
+    if opt.preflight {
+        return Ok(0);
+    }
+
+    if let Some(replay_dir) = &opt.replay_dir {
+        return replay(opt, replay_dir);
+    }
 
-    let inbox = imap_session.select("INBOX").unwrap();
-    let message_count = inbox.exists;
-    let messages = imap_session.fetch("1:*", "RFC822").unwrap();
+    let password = get_password(opt).map_err(|e| anyhow!("Could not get password: {}", e))?;
 
-    println!("Connected to IMAP server.");
+    let mut manifest = match &opt.manifest {
+        Some(manifest_path) => Manifest::load(manifest_path).expect("Could not read manifest."),
+        None => Manifest::default(),
+    };
 
-    for message in messages.iter() {
-        println!(
-            "{:.2} % done",
-            100.00 / message_count as f32 * message.message as f32
-        );
-        if let Some(body) = message.body() {
-            let mail = parse_mail(body).unwrap();
-            let message_id = mail.headers.get_first_value("Message-ID").unwrap();
+    let error_log_path = opt.redownload_failed.as_ref().or(opt.error_log.as_ref());
+    let mut error_log = match error_log_path {
+        Some(error_log_path) => ErrorLog::load(error_log_path).expect("Could not read error log."),
+        None => ErrorLog::default(),
+    };
+
+    let mut state = match &opt.state {
+        Some(state_path) => State::load(state_path).expect("Could not read state file."),
+        None => State::default(),
+    };
+
+    let enrichment = load_enrichment(opt).expect("Could not load the enrichment");
 
-            let attachment = match get_attachment(&mail) {
-                Ok(attachment) => attachment,
+    log!(
+        "Will connect to {} on port {} with account '{}'",
+        server,
+        port,
+        account
+    );
+    let mut tls_builder = TlsConnector::builder();
+    if let Some(client_cert) = &opt.client_cert {
+        let identity = load_identity(client_cert, opt.client_key.as_deref())
+            .expect("Could not load client certificate");
+        tls_builder.identity(identity);
+    }
+    let tls = Connector::Native(tls_builder.build().unwrap());
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    let tls = match opt.tls_expired_grace {
+        Some(days) => Connector::with_expired_grace(days)?,
+        None => tls,
+    };
+    let connect_timeout = Duration::from_secs(opt.connect_timeout);
+    let keep_connection = idle.is_some();
+    let mut idle = idle;
+    let reusable = idle
+        .as_mut()
+        .and_then(|idle| idle.take())
+        .filter(|connection| connection.server == server && connection.port == port);
+    let session = match reusable {
+        Some(connection) => {
+            log!("Reusing the connection to {}:{}", server, port);
+            match login(connection.stream.clone(), account, &password, opt.compress) {
+                Ok(session) => Ok((connection.stream, session)),
+                Err(e) if e.is::<LoginRejected>() => {
+                    // Still unauthenticated, so the next account can try
+                    if let Some(idle) = idle.as_mut() {
+                        **idle = Some(connection);
+                    }
+                    Err(e)
+                }
                 Err(e) => {
-                    eprintln!("{} Message: {}", e, message_id);
-                    continue;
+                    eprintln!("Could not reuse the connection: {}", e);
+                    open_session(
+                        server,
+                        port,
+                        &tls,
+                        account,
+                        &password,
+                        opt.compress,
+                        connect_timeout,
+                    )
                 }
-            };
+            }
+        }
+        None => open_session(
+            server,
+            port,
+            &tls,
+            account,
+            &password,
+            opt.compress,
+            connect_timeout,
+        ),
+    };
+    let (mut stream, mut imap_session) = match session {
+        Ok(session) => session,
+        Err(e) if e.is::<LoginRejected>() => return Err(e),
+        Err(e) => return Err(anyhow!("Error connecting to server: {}", e)),
+    };
+
+    let capabilities = match imap_session.capabilities() {
+        Ok(capabilities) => capabilities,
+        Err(e) => {
+            if let Err(e) = imap_session.logout() {
+                eprintln!("Could not log out: {}", e);
+            }
+            return Err(ExtractError::CapabilitiesFailed(e.to_string()).into());
+        }
+    };
+    let supported: Vec<&str> = RELEVANT_CAPABILITIES
+        .iter()
+        .copied()
+        .filter(|cap| capabilities.has_str(cap))
+        .collect();
+    if supported.is_empty() {
+        log!(
+            "Server supports none of the extensions: {}",
+            RELEVANT_CAPABILITIES.join(", ")
+        );
+    } else {
+        log!("Supported extensions: {}", supported.join(", "));
+    }
+
+    let unauthenticate_supported = capabilities.has_str("UNAUTHENTICATE");
+    // Both provide the HIGHESTMODSEQ of a mailbox, which changes with every new message
+    let modseq_supported = capabilities.has_str("QRESYNC") || capabilities.has_str("CONDSTORE");
+    let sort_supported = capabilities.has_str("SORT");
+    if opt.newest_first {
+        if sort_supported {
+            log!("Using SORT to order the messages by their Date header");
+        } else {
+            log!("Server doesn't support SORT, fetching the Date headers to order the messages");
+        }
+    }
+    if opt.state.is_some() {
+        if modseq_supported {
+            log!("Using HIGHESTMODSEQ to skip folders without changes since the last run");
+        } else {
+            log!("Server supports neither QRESYNC nor CONDSTORE, searching the UID range");
+        }
+    }
+    let namespace = if capabilities.has_str("NAMESPACE") {
+        get_personal_namespace(&stream)
+    } else {
+        None
+    };
+    log!("Connected to IMAP server.");
+
+    let default_folders = if opt.auto_folder {
+        vec![find_dmarc_folder(&mut imap_session)]
+    } else {
+        vec![String::from("INBOX")]
+    };
+    let failed_folders = error_log.folders();
+    let folders = if opt.redownload_failed.is_some() {
+        &failed_folders
+    } else if opt.folders.is_empty() {
+        &default_folders
+    } else {
+        &opt.folders
+    };
 
-            let attachment = decompress_attachment(attachment).unwrap();
+    let started = chrono::Utc::now().timestamp();
+    let since = if opt.incremental {
+        state.last_success
+    } else {
+        let today = chrono::Utc::now().date_naive();
+        opt.since_days.map(|days| {
+            since_date(today, days)
+                .and_time(chrono::NaiveTime::MIN)
+                .and_utc()
+                .timestamp()
+        })
+    };
 
-            let mut filepath = path.clone();
-            filepath.push(attachment.name.clone());
-            let mut file = File::create(&filepath).expect("Could not create file.");
-            match file.write_all(&attachment.decompressed.unwrap()) {
-                Ok(()) => (),
-                Err(e) => eprintln!("{}", e),
+    let deadline = opt
+        .max_runtime
+        .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+    if let Some(days) = opt.dedupe_window {
+        state.prune_reports(started, days);
+    }
+    let mut summary = Summary {
+        enrichment,
+        known_reports: std::mem::take(&mut state.reports),
+        ..Summary::default()
+    };
+    let mut reconnects = 0;
+    'folders: for folder in folders {
+        loop {
+            if opt.limit.is_some_and(|limit| summary.messages >= limit) || summary.too_many_reports
+            {
+                break 'folders;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                log!("Maximum runtime reached, skipping folder '{}'", folder);
+                summary.deadline_reached = true;
+                continue 'folders;
+            }
+            let (folder, mailbox, highest_modseq) =
+                match select_folder(&mut imap_session, folder, namespace.as_deref()) {
+                    Ok(selected) => selected,
+                    Err(e) => {
+                        eprintln!("Could not select folder '{}': {}", folder, e);
+                        summary.failed_folders += 1;
+                        continue 'folders;
+                    }
+                };
+            log!("Using folder '{}'", folder);
+            let highest_modseq = highest_modseq
+                .filter(|_| modseq_supported && opt.state.is_some())
+                .filter(|_| opt.redownload_failed.is_none());
+
+            // Retrying failed messages must not move the watermarks of the state
+            let mut retry_state = FolderState::default();
+            let folder_state = if opt.redownload_failed.is_some() {
+                &mut retry_state
+            } else {
+                state.folder(&folder)
             };
+            let result = process_folder(
+                &mut imap_session,
+                opt,
+                &folder,
+                &mailbox,
+                folder_state,
+                since,
+                highest_modseq,
+                Some(&stream).filter(|_| sort_supported),
+                deadline,
+                &mut manifest,
+                &mut error_log,
+                &mut summary,
+            );
+            match result {
+                Ok(()) => break,
+                Err(e) if is_connection_error(&e) => {
+                    // Continues with the next message after the UID watermark
+                    eprintln!("Connection to the server lost: {}", e);
+                    loop {
+                        if reconnects >= opt.max_reconnects {
+                            eprintln!("Giving up after {} reconnects.", reconnects);
+                            summary.failed_folders += 1;
+                            break 'folders;
+                        }
+                        reconnects += 1;
+                        log!("Reconnecting ({}/{})", reconnects, opt.max_reconnects);
+                        match open_session(
+                            server,
+                            port,
+                            &tls,
+                            account,
+                            &password,
+                            opt.compress,
+                            connect_timeout,
+                        ) {
+                            Ok((new_stream, session)) => {
+                                stream = new_stream;
+                                imap_session = session;
+                                break;
+                            }
+                            Err(e) if e.is::<LoginRejected>() => {
+                                // Only transient errors are worth another attempt
+                                eprintln!("{}", e);
+                                summary.failed_folders += 1;
+                                break 'folders;
+                            }
+                            Err(e) => eprintln!("Could not reconnect: {}", e),
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Could not process folder '{}': {}", folder, e);
+                    summary.failed_folders += 1;
+                    break;
+                }
+            }
         }
     }
-    imap_session.logout().unwrap();
-    println!("Finished!");
-}
+    let kept = keep_connection && unauthenticate_supported && {
+        // RFC 8437, the connection returns to the state right after the greeting
+        match stream.raw_command("UNAUTHENTICATE") {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Could not unauthenticate: {}", e);
+                false
+            }
+        }
+    };
+    if kept {
+        if let Some(idle) = idle {
+            *idle = Some(IdleConnection {
+                server: server.to_string(),
+                port,
+                stream,
+            });
+        }
+    } else if let Err(e) = imap_session.logout() {
+        eprintln!("Could not log out: {}", e);
+    }
 
-fn decompress_attachment(mut attachment: Attachment) -> Result<Attachment> {
-    // Decompresses the attachment, saves it in te Attachment struct and returns it
+    if opt.count_only {
+        log!("{} messages in total.", summary.messages);
+        return Ok(0);
+    }
 
-    let content = std::io::Cursor::new(&attachment.content);
-    let mut decompressed: Vec<u8> = Vec::new();
-    // TODO: add function that determines type better, e.g. check file extension if mimetype is
-    // octect stream
-    if attachment.mimetype == String::from("application/zip") {
-        let mut zip = ZipArchive::new(content).unwrap();
-        let mut report = zip.by_index(0)?;
-        std::io::copy(&mut report, &mut decompressed)?;
-        attachment.name = String::from(report.name());
-    } else if attachment.mimetype == String::from("application/gzip")
-        || attachment.mimetype == String::from("application/octet-stream")
+    // The state is not updated if this fails, so the reports are merged again in the next run
+    write_merged(opt, &summary)?;
+    if let Err(e) = write_errors(opt, &summary) {
+        eprintln!("Could not write errors file: {}", e);
+    }
+    if summary.failed == 0
+        && summary.failed_folders == 0
+        && !summary.deadline_reached
+        && opt.redownload_failed.is_none()
     {
-        let mut report = Decoder::new(content).unwrap();
-        std::io::copy(&mut report, &mut decompressed)?;
-        let mut path = PathBuf::from(attachment.name.clone());
-        path = path.with_extension("");
-        attachment.name = String::from(path.to_str().unwrap());
+        state.last_success = Some(started);
     }
-    attachment.decompressed = Some(decompressed);
-
-    Ok(attachment)
+    #[cfg(feature = "serve")]
+    metrics::update(|metrics| {
+        let (messages, passed) = summary.statistics.totals();
+        metrics.reports += summary.reports as u64;
+        metrics.tls_reports += summary.tls_reports as u64;
+        metrics.forensic_reports += summary.forensic_reports as u64;
+        metrics.failed_messages += summary.failed as u64;
+        metrics.passed += passed;
+        metrics.failed += messages - passed;
+    });
+    state.reports = std::mem::take(&mut summary.known_reports);
+    match &opt.state {
+        Some(state_path) if !opt.dry_run => {
+            state.save(state_path).expect("Could not write state file.")
+        }
+        _ => (),
+    }
+    match &opt.manifest {
+        Some(manifest_path) if !opt.dry_run => manifest
+            .save(manifest_path)
+            .expect("Could not write manifest."),
+        _ => (),
+    }
+    match error_log_path {
+        Some(error_log_path) if !opt.dry_run => error_log
+            .save(error_log_path)
+            .expect("Could not write error log."),
+        _ => (),
+    }
+    if !opt.dry_run {
+        if let Err(e) = summary.enrichment.save() {
+            eprintln!("Could not write enrichment cache: {}", e);
+        }
+    }
+    print_summary(opt, &summary);
+    let plain_summary = !opt.quiet && !opt.pretty_summary;
+    if summary.failed > 0 && plain_summary {
+        log!("{} messages could not be fetched.", summary.failed);
+    }
+    if summary.failed_folders > 0 && plain_summary {
+        log!("{} folders could not be selected.", summary.failed_folders);
+    }
+    if error_log_path.is_some() && error_log.len() > 0 && !opt.quiet {
+        log!("{} messages are listed in the error log.", error_log.len());
+    }
+    if !reports_found(opt, &summary) || summary.too_many_reports {
+        return Ok(1);
+    }
+    if summary.deadline_reached {
+        return Ok(DEADLINE_EXIT_CODE);
+    }
+    Ok(0)
 }
 
-fn get_attachment(mail: &ParsedMail) -> Result<Attachment> {
-    // Extracts the attachment from the mail
+fn replay(opt: &Opt, dir: &Path) -> Result<i32> {
+    // Runs saved messages through the same extraction as the messages fetched from the server
 
-    let mut content_type = mail.ctype.mimetype.clone();
-    let mut body: Vec<u8> = vec![];
-    let mut name = String::new();
+    let mut manifest = match &opt.manifest {
+        Some(manifest_path) => Manifest::load(manifest_path)?,
+        None => Manifest::default(),
+    };
 
-    if USABLE_MIMETYPES.contains(&content_type.as_str()) {
-        body = mail.get_body_raw().unwrap().clone();
-        name = mail
-            .get_content_disposition()
-            .params
-            .get("filename")
-            .unwrap()
-            .clone();
-    } else if mail.subparts.len() > 0 {
-        for subpart in &mail.subparts {
-            content_type = subpart.ctype.mimetype.clone();
-            if USABLE_MIMETYPES.contains(&content_type.as_str()) {
-                body = subpart.get_body_raw()?;
-                name = subpart
-                    .get_content_disposition()
-                    .params
-                    .get("filename")
-                    .unwrap()
-                    .clone();
-                break;
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut summary = Summary {
+        enrichment: load_enrichment(opt)?,
+        ..Summary::default()
+    };
+    for path in &paths {
+        log!("Replaying {}", path.display());
+        let body = std::fs::read(path)?;
+        let mail = match parse_mail(&body) {
+            Ok(mail) => mail,
+            Err(e) => {
+                eprintln!("Could not parse message {}: {}", path.display(), e);
+                continue;
             }
+        };
+        let message_id = mail
+            .headers
+            .get_first_value("Message-ID")
+            .unwrap_or_else(|| path.display().to_string());
+        summary.messages += 1;
+        // Failures are already printed, there is nothing to retry
+        let first_error = summary.errors.len();
+        let _ = process_message(opt, &mail, &message_id, &mut manifest, &mut summary);
+        let mimetypes = part_mimetypes(&mail);
+        for failure in &mut summary.errors[first_error..] {
+            failure.folder = path.display().to_string();
+            failure.mimetypes = mimetypes.clone();
+        }
+        if summary.too_many_reports {
+            break;
         }
     }
 
-    if body.is_empty() {
-        return Err(anyhow!("No attachment found."));
+    write_merged(opt, &summary)?;
+    write_errors(opt, &summary)?;
+    match &opt.manifest {
+        Some(manifest_path) if !opt.dry_run => manifest.save(manifest_path)?,
+        _ => (),
     }
-    if name.is_empty() {
-        return Err(anyhow!("No file name found."));
+    if !opt.dry_run {
+        summary.enrichment.save()?;
+    }
+    print_summary(opt, &summary);
+    if !reports_found(opt, &summary) || summary.too_many_reports {
+        return Ok(1);
     }
 
-    Ok(Attachment {
-        content: body,
-        decompressed: None,
-        name: name,
-        mimetype: content_type,
-    })
+    Ok(0)
+}
+
+fn load_enrichment(opt: &Opt) -> Result<Enrichment> {
+    // Lookups only end up in the JSON output, so they are not done for anything else
+
+    #[cfg(unix)]
+    let resolve_ptr = opt.resolve_ptr;
+    #[cfg(not(unix))]
+    let resolve_ptr = false;
+    let enrichment = Enrichment::new(
+        resolve_ptr,
+        &opt.geoip_dbs,
+        opt.enrichment_cache.as_deref(),
+        opt.enrichment_ttl,
+        opt.lookup_concurrency,
+    )?;
+    if enrichment.is_enabled() && !opt.json_stream && !opt.merge_by_domain {
+        eprintln!("--resolve-ptr and --geoip-db only have an effect with --json-stream or --merge-by-domain");
+        return Ok(Enrichment::default());
+    }
+    Ok(enrichment)
+}
+
+fn run_batch(opt: &Opt, path: &Path) -> i32 {
+    // Processes the accounts one after the other, each with its own session, state and summary.
+    // Returns the exit code for all of them.
+
+    let accounts = match AccountConfig::load_all(path) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            eprintln!("Could not read accounts file {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+
+    let mut results: Vec<Option<Result<i32>>> = Vec::new();
+    let mut idle: Option<IdleConnection> = None;
+    for config in &accounts {
+        if idle
+            .as_ref()
+            .is_some_and(|connection| !connection.is_for(&config.server))
+        {
+            close_idle(idle.take());
+        }
+        log!("Processing account {}", config.label());
+        let result = config.password().and_then(|password| {
+            let mut account_opt = opt.clone();
+            account_opt.accounts = None;
+            account_opt.server = Some(config.server.clone());
+            account_opt.account = Some(config.account.clone());
+            account_opt.password = password;
+            account_opt.path = Some(config.path.clone());
+            account_opt.state = config.state.clone();
+            account_opt.error_log = config.error_log.clone();
+            if !config.folders.is_empty() {
+                account_opt.folders = config.folders.clone();
+                account_opt.auto_folder = false;
+            }
+            run(&account_opt, Some(&mut idle))
+        });
+        if let Err(e) = &result {
+            eprintln!("Account {} failed: {}", config.label(), e);
+        }
+        let failed = !matches!(result, Ok(0));
+        results.push(Some(result));
+        if failed && opt.fail_fast {
+            eprintln!("Stopping, as --fail-fast is given.");
+            break;
+        }
+    }
+    close_idle(idle);
+    results.resize_with(accounts.len(), || None);
+
+    if !opt.quiet {
+        log!();
+        log!("Accounts:");
+        for (config, result) in accounts.iter().zip(&results) {
+            let outcome = match result {
+                Some(Ok(0)) => String::from("ok"),
+                Some(Ok(DEADLINE_EXIT_CODE)) => String::from("maximum runtime reached"),
+                Some(Ok(_)) => String::from("failed"),
+                Some(Err(e)) => format!("failed, {}", e),
+                None => String::from("skipped"),
+            };
+            log!("  {}: {}", config.label(), outcome);
+        }
+    }
+
+    // Failures take precedence over accounts that ran out of time
+    let codes: Vec<i32> = results
+        .iter()
+        .map(|result| match result {
+            Some(Ok(code)) => *code,
+            Some(Err(_)) => 1,
+            None => 0,
+        })
+        .collect();
+    if codes
+        .iter()
+        .any(|code| *code != 0 && *code != DEADLINE_EXIT_CODE)
+    {
+        1
+    } else {
+        codes.into_iter().max().unwrap_or_default()
+    }
+}
+
+/// Unauthenticated connection kept for the next account on the same server
+struct IdleConnection {
+    server: String,
+    port: u16,
+    stream: SharedStream,
+}
+
+impl IdleConnection {
+    fn is_for(&self, server: &str) -> bool {
+        let mut parts = server.split(':');
+        let host = parts.next().unwrap_or_default();
+        let port = parts.next().map_or(Some(993), |port| port.parse().ok());
+        host == self.server && port == Some(self.port)
+    }
+}
+
+fn close_idle(idle: Option<IdleConnection>) {
+    if let Some(connection) = idle {
+        if let Err(e) = connection.stream.raw_command("LOGOUT") {
+            eprintln!("Could not log out: {}", e);
+        }
+    }
+}
+
+fn reports_found(opt: &Opt, summary: &Summary) -> bool {
+    // An empty run may point to a broken mail rule, monitoring can alert on the exit code
+
+    if opt.fail_on_no_reports && summary.reports == 0 {
+        eprintln!("No DMARC reports found.");
+        return false;
+    }
+    if let Some(report_id) = &opt.report_id {
+        if summary.reports + summary.tls_reports == 0 {
+            eprintln!("Report {} not found.", report_id);
+            return false;
+        }
+    }
+    true
+}
+
+fn print_summary(opt: &Opt, summary: &Summary) {
+    if opt.summary_only {
+        summary.statistics.print();
+        log!();
+    }
+    if opt.coverage {
+        summary.coverage.print();
+        log!();
+    }
+    if opt.reporters {
+        summary.reporters.print();
+        log!();
+    }
+    if let Some(limit) = opt.top_sources {
+        summary.sources.print(limit);
+        log!();
+    }
+    if opt.quiet {
+        return;
+    }
+    if opt.pretty_summary {
+        print_pretty_summary(opt, summary);
+        return;
+    }
+    log!("Finished! {} reports extracted.", summary.reports);
+    if opt.diff {
+        log!(
+            "{} reports would be created, {} updated, {} are unchanged.",
+            summary.would_create,
+            summary.would_update,
+            summary.unchanged
+        );
+    }
+    if summary.tls_reports > 0 {
+        log!("{} SMTP TLS reports extracted.", summary.tls_reports);
+    }
+    if summary.forensic_reports > 0 {
+        log!(
+            "{} DMARC failure reports extracted.",
+            summary.forensic_reports
+        );
+    }
+    if summary.duplicates > 0 {
+        log!(
+            "{} reports were skipped as they were already extracted.",
+            summary.duplicates
+        );
+    }
+    if let Some(days) = opt.warn_older_than {
+        if summary.old_reports > 0 {
+            log!(
+                "{} reports ended more than {} days ago.",
+                summary.old_reports,
+                days
+            );
+        }
+    }
+    if summary.deadline_reached {
+        log!(
+            "The maximum runtime was reached, {} messages of the processed folders were left for the \
+            next run.",
+            summary.unprocessed
+        );
+    }
+    if let Some(dir) = &opt.quarantine_dir {
+        if summary.quarantined > 0 {
+            log!(
+                "{} reports were quarantined in {}.",
+                summary.quarantined,
+                dir.display()
+            );
+        }
+    }
+}
+
+fn log_is_terminal() -> bool {
+    if LOG_TO_STDERR.load(Ordering::Relaxed) {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    }
+}
+
+const GREEN: &str = "32";
+const RED: &str = "31";
+const YELLOW: &str = "33";
+
+fn print_pretty_summary(opt: &Opt, summary: &Summary) {
+    // Counts that are fine are green, failures red and warnings yellow
+
+    let colored = log_is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let line = |label: &str, count: usize, color: &str| {
+        let count = if colored {
+            format!("\x1b[{}m{:>6}\x1b[0m", color, count)
+        } else {
+            format!("{:>6}", count)
+        };
+        log!("{:<20}{}", label, count);
+    };
+    let failure = |count: usize| if count > 0 { RED } else { GREEN };
+
+    log!("Finished!");
+    line("Reports extracted", summary.reports, GREEN);
+    if summary.tls_reports > 0 {
+        line("SMTP TLS reports", summary.tls_reports, GREEN);
+    }
+    if summary.forensic_reports > 0 {
+        line("Failure reports", summary.forensic_reports, GREEN);
+    }
+    if opt.dedupe_window.is_some() {
+        line("Already extracted", summary.duplicates, GREEN);
+    }
+    line("Failed messages", summary.failed, failure(summary.failed));
+    line(
+        "Failed folders",
+        summary.failed_folders,
+        failure(summary.failed_folders),
+    );
+    if opt.warn_older_than.is_some() {
+        let color = if summary.old_reports > 0 {
+            YELLOW
+        } else {
+            GREEN
+        };
+        line("Old reports", summary.old_reports, color);
+    }
+    if opt.max_runtime.is_some() {
+        let color = if summary.deadline_reached {
+            YELLOW
+        } else {
+            GREEN
+        };
+        line("Not processed", summary.unprocessed, color);
+    }
+    if opt.quarantine_dir.is_some() {
+        let color = if summary.quarantined > 0 {
+            YELLOW
+        } else {
+            GREEN
+        };
+        line("Quarantined", summary.quarantined, color);
+    }
+    if opt.diff {
+        line("Would be created", summary.would_create, GREEN);
+        line("Would be updated", summary.would_update, YELLOW);
+        line("Unchanged", summary.unchanged, GREEN);
+    }
+}
+
+fn check_report_age(opt: &Opt, report: &Feedback, message_id: &str, summary: &mut Summary) {
+    // Old reports point to delays in the delivery of the reports, they are stored anyway
+
+    let days = match opt.warn_older_than {
+        Some(days) => days,
+        None => return,
+    };
+    let age = chrono::Utc::now().timestamp() - report.report_metadata.date_range.end;
+    if age > days as i64 * 86400 {
+        eprintln!(
+            "Warning: Report {} ended {} days ago. Message: {}",
+            report.report_metadata.report_id,
+            age / 86400,
+            message_id
+        );
+        summary.old_reports += 1;
+    }
+}
+
+/// Limits the rate of --max-messages-per-second
+struct Pacer {
+    interval: Option<Duration>,
+    next_message: Instant,
+}
+
+impl Pacer {
+    fn new(rate: Option<u32>) -> Self {
+        Pacer {
+            interval: rate
+                .filter(|rate| *rate > 0)
+                .map(|rate| Duration::from_secs(1) / rate),
+            next_message: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        // Simple pacing, the next message starts one interval after the previous one
+
+        if let Some(interval) = self.interval {
+            let now = Instant::now();
+            if self.next_message > now {
+                std::thread::sleep(self.next_message - now);
+            }
+            self.next_message = Instant::now().max(self.next_message) + interval;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_folder(
+    imap_session: &mut ImapSession,
+    opt: &Opt,
+    folder: &str,
+    mailbox: &imap::types::Mailbox,
+    state: &mut FolderState,
+    since: Option<i64>,
+    highest_modseq: Option<u64>,
+    sort_stream: Option<&SharedStream>,
+    deadline: Option<Instant>,
+    manifest: &mut Manifest,
+    error_log: &mut ErrorLog,
+    summary: &mut Summary,
+) -> Result<(), imap::error::Error> {
+    // Fetches and extracts all new messages of the selected folder. Stops if the connection is
+    // lost, the state then allows to continue where it stopped.
+
+    if !state.check_uid_validity(mailbox.uid_validity) {
+        eprintln!(
+            "Warning: UIDVALIDITY of the mailbox changed, the mailbox was probably recreated. \
+            Processing all messages again."
+        );
+        state.highest_modseq = None;
+    }
+    if let Some(modseq) = highest_modseq {
+        // Nothing changed in the mailbox, not even flags, so there can't be new messages
+        if state.highest_modseq == Some(modseq) && opt.since_uid.is_none() && !opt.count_only {
+            log!(
+                "Folder '{}' is unchanged since the last run (HIGHESTMODSEQ {}), skipping it",
+                folder,
+                modseq
+            );
+            return Ok(());
+        }
+        if opt.verbose {
+            log!("Using HIGHESTMODSEQ {} to detect changes next time", modseq);
+        }
+    }
+    let first_uid = match (opt.since_uid, state.last_uid) {
+        (Some(uid), _) => uid,
+        (None, Some(uid)) if !opt.only_new_since_last_run && !opt.incremental => uid + 1,
+        _ => 1,
+    };
+    if let (Some(uid), Some(uid_next)) = (opt.since_uid, mailbox.uid_next) {
+        if uid >= uid_next {
+            eprintln!(
+                "Warning: There is no message with UID {} or higher yet (UIDNEXT is {}), the UID \
+                may be from before the mailbox was recreated.",
+                uid, uid_next
+            );
+        }
+    }
+    let last_date = state.last_date;
+    // A message arrives after its Date, so older messages don't have to be searched. A day is
+    // added as the server compares the days in its own time zone.
+    let since = if opt.only_new_since_last_run {
+        since.max(last_date.map(|date| date - 86400))
+    } else {
+        since
+    };
+
+    let mut uids: Vec<u32> = if opt.redownload_failed.is_some() {
+        error_log.uids(folder, mailbox.uid_validity)
+    } else {
+        // "n:*" always contains the message with the highest UID, even if it is lower than n
+        imap_session
+            .uid_search(format!(
+                "UID {}:*{}{}",
+                first_uid,
+                since_query(since),
+                if opt.only_unseen { " UNSEEN" } else { "" }
+            ))?
+            .into_iter()
+            .filter(|uid| *uid >= first_uid && !state.is_processed(*uid))
+            .collect()
+    };
+    uids.sort_unstable();
+    if let Some(max_size) = opt.max_message_size {
+        uids = filter_by_size(imap_session, opt, &uids, max_size)?;
+    }
+    if opt.newest_first {
+        uids = match sort_stream.and_then(|stream| sort_by_server(stream, &uids)) {
+            Some(sorted) => sorted,
+            None => sort_newest_first(imap_session, opt, &uids)?,
+        };
+    }
+    if let Some(max) = opt.max_messages_per_folder {
+        // Keeps the newest messages, by their Date header with --newest-first, otherwise the
+        // ones with the highest UIDs
+        if opt.newest_first {
+            uids.truncate(max);
+        } else {
+            uids.drain(..uids.len().saturating_sub(max));
+        }
+    }
+    // Messages beyond --limit are left for the next run. With --newest-first they are the older
+    // ones, the newer ones are remembered in the state as they are above the watermark
+    let over_limit = match opt.limit {
+        Some(limit) => uids.split_off(limit.saturating_sub(summary.messages).min(uids.len())),
+        None => Vec::new(),
+    };
+    let complete = over_limit.is_empty();
+    summary.messages += uids.len();
+
+    if opt.count_only {
+        log!("{} messages to process", uids.len());
+        return Ok(());
+    }
+
+    // RFC822 marks the messages as seen, BODY.PEEK[] leaves them untouched
+    let peek = opt.dry_run || opt.all;
+    let query = if peek { "BODY.PEEK[]" } else { "RFC822" };
+    let batch_query = if opt.partial_fetch {
+        "BODYSTRUCTURE"
+    } else {
+        query
+    };
+    let mut processed = 0;
+    let mut failed_uids: Vec<u32> = Vec::new();
+    // Progress lines are only noise in logs, e.g. of cron jobs
+    let show_progress = !opt.no_progress && (opt.force_progress || log_is_terminal());
+    let mut pacer = Pacer::new(opt.max_messages_per_second);
+    let mut result = Ok(());
+    let mut handled: HashSet<u32> = HashSet::new();
+    let mut unprocessed: Vec<u32> = Vec::new();
+    let mut deadline_reached = false;
+
+    'batches: for batch in uids.chunks(opt.batch_size.max(1)) {
+        let uid_set = join_uids(batch);
+        let fetches = match imap_session.uid_fetch(&uid_set, batch_query) {
+            Ok(messages) => vec![messages],
+            Err(e) if is_connection_error(&e) => {
+                result = Err(e);
+                break;
+            }
+            Err(e) => {
+                // Retry the messages one by one to only skip the ones the server fails on
+                eprintln!("Could not fetch messages {}: {}", uid_set, e);
+                let mut fetches = Vec::new();
+                for uid in batch {
+                    match imap_session.uid_fetch(uid.to_string(), batch_query) {
+                        Ok(messages) => fetches.push(messages),
+                        Err(e) if is_connection_error(&e) => {
+                            result = Err(e);
+                            break 'batches;
+                        }
+                        Err(e) => {
+                            eprintln!("Could not fetch message {}: {}", uid, e);
+                            failed_uids.push(*uid);
+                            let failure = FailedMessage {
+                                folder: String::from(folder),
+                                uid: *uid,
+                                uid_validity: mailbox.uid_validity,
+                                kind: Some(String::from("fetch")),
+                                reason: format!("Could not fetch message: {}", e),
+                                ..Default::default()
+                            };
+                            summary.errors.push(failure.clone());
+                            error_log.add(failure);
+                        }
+                    }
+                }
+                fetches
+            }
+        };
+
+        let mut messages: Vec<&imap::types::Fetch> = fetches
+            .iter()
+            .flat_map(|messages| messages.iter())
+            .collect();
+        if opt.newest_first {
+            // The server returns the messages of a batch in the order of the mailbox
+            messages.sort_by_key(|message| batch.iter().position(|uid| message.uid == Some(*uid)));
+        }
+        for message in messages {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                unprocessed = uids
+                    .iter()
+                    .copied()
+                    .filter(|uid| !handled.contains(uid) && !failed_uids.contains(uid))
+                    .collect();
+                deadline_reached = true;
+                break 'batches;
+            }
+            pacer.wait();
+            let uid = message.uid.unwrap_or(0);
+            handled.insert(uid);
+            let partial = if opt.partial_fetch {
+                match fetch_report_part(imap_session, message, query, peek) {
+                    Ok(partial) => partial,
+                    Err(e) if is_connection_error(&e) => {
+                        result = Err(e);
+                        break 'batches;
+                    }
+                    Err(e) => {
+                        eprintln!("Could not fetch message {}: {}", uid, e);
+                        failed_uids.push(uid);
+                        let failure = FailedMessage {
+                            folder: String::from(folder),
+                            uid,
+                            uid_validity: mailbox.uid_validity,
+                            kind: Some(String::from("fetch")),
+                            reason: format!("Could not fetch message: {}", e),
+                            ..Default::default()
+                        };
+                        summary.errors.push(failure.clone());
+                        error_log.add(failure);
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            state.last_uid = Some(state.last_uid.map_or(uid, |last| last.max(uid)));
+            error_log.remove(folder, uid);
+
+            processed += 1;
+            if show_progress {
+                log!(
+                    "{:.2} % done",
+                    100.00 / uids.len() as f32 * processed as f32
+                );
+            }
+            let body = match partial {
+                Some(partial) => Cow::Owned(partial),
+                None => match message_body(message, opt.verbose) {
+                    Some(body) => body,
+                    None => {
+                        // Retried like a failed fetch, the server may send it next time
+                        eprintln!("Server sent no content for message {}, skipping it", uid);
+                        failed_uids.push(uid);
+                        let failure = FailedMessage {
+                            folder: String::from(folder),
+                            uid,
+                            uid_validity: mailbox.uid_validity,
+                            kind: Some(String::from("no-content")),
+                            reason: String::from("Server sent no content"),
+                            ..Default::default()
+                        };
+                        summary.errors.push(failure.clone());
+                        error_log.add(failure);
+                        continue;
+                    }
+                },
+            };
+            if let Some(dir) = opt.save_eml.as_ref().filter(|_| !opt.dry_run) {
+                if let Err(e) = save_eml(dir, folder, mailbox.uid_validity, uid, &body) {
+                    eprintln!("Could not save message {}: {}", uid, e);
+                }
+            }
+            let mail = match parse_mail(&body) {
+                Ok(mail) => mail,
+                Err(e) => {
+                    // The same content would fail again, so it is not retried
+                    eprintln!("Could not parse message {}: {}", uid, e);
+                    summary.failed += 1;
+                    summary.errors.push(FailedMessage {
+                        folder: String::from(folder),
+                        uid,
+                        uid_validity: mailbox.uid_validity,
+                        kind: Some(String::from("parse-message")),
+                        reason: format!("Could not parse message: {}", e),
+                        ..Default::default()
+                    });
+                    continue;
+                }
+            };
+
+            // Messages without a parsable Date header are always processed
+            if let Some(date) = get_date(&mail) {
+                if opt.only_new_since_last_run && last_date.is_some_and(|last| date <= last) {
+                    continue;
+                }
+                state.last_date = Some(state.last_date.map_or(date, |last| last.max(date)));
+            }
+
+            let message_id = mail
+                .headers
+                .get_first_value("Message-ID")
+                .unwrap_or_else(|| format!("UID {}", uid));
+
+            let first_error = summary.errors.len();
+            let result = process_message(opt, &mail, &message_id, manifest, summary);
+            // process_message only knows the message, not where it came from
+            let mimetypes = part_mimetypes(&mail);
+            for failure in &mut summary.errors[first_error..] {
+                failure.folder = String::from(folder);
+                failure.uid = uid;
+                failure.uid_validity = mailbox.uid_validity;
+                failure.mimetypes = mimetypes.clone();
+            }
+            if summary.too_many_reports {
+                // The report of this message was not written, it is left for the next run
+                handled.remove(&uid);
+                unprocessed = uids
+                    .iter()
+                    .copied()
+                    .filter(|uid| !handled.contains(uid) && !failed_uids.contains(uid))
+                    .collect();
+                summary.messages -= unprocessed.len();
+                break 'batches;
+            }
+            if let Err(reason) = result {
+                error_log.add(FailedMessage {
+                    folder: String::from(folder),
+                    uid,
+                    uid_validity: mailbox.uid_validity,
+                    message_id: Some(message_id),
+                    reason,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    if deadline_reached {
+        log!(
+            "Maximum runtime reached, {} messages of folder '{}' were not processed",
+            unprocessed.len(),
+            folder
+        );
+        summary.messages -= unprocessed.len();
+        summary.unprocessed += unprocessed.len();
+        summary.deadline_reached = true;
+    }
+    // Messages the server failed to send are retried with --redownload-failed. Stopping below them
+    // would fetch all later messages again on every run.
+    if let Some(uid) = failed_uids.iter().max() {
+        state.last_uid = Some(state.last_uid.map_or(*uid, |last| last.max(*uid)));
+    }
+    summary.failed += failed_uids.len();
+    if result.is_err() {
+        // Fetched after reconnecting, with --newest-first they can be below the watermark
+        unprocessed = uids
+            .iter()
+            .copied()
+            .filter(|uid| !handled.contains(uid) && !failed_uids.contains(uid))
+            .collect();
+        summary.messages -= unprocessed.len();
+    }
+    // Messages that were not reached are processed after reconnecting or on the next run. The
+    // ones above them that were processed are kept in the state, so they are not fetched again.
+    let first_unprocessed = unprocessed.iter().chain(&over_limit).min().copied();
+    if let Some(uid) = first_unprocessed {
+        state.last_uid = Some(uid - 1);
+    }
+    state.add_processed(handled.iter().chain(&failed_uids).copied());
+    if first_unprocessed.is_none() {
+        state.cover_processed();
+    }
+    // The folder may only be skipped next time if all of its messages were processed. Marking
+    // them as seen changed the HIGHESTMODSEQ, so it is taken from selecting the folder again. If
+    // a message arrived in the meantime, the folder is searched next time.
+    state.highest_modseq = highest_modseq
+        .filter(|_| complete && result.is_ok() && failed_uids.is_empty() && unprocessed.is_empty())
+        .and_then(|_| match select_mailbox(imap_session, folder) {
+            Ok((reselected, modseq)) if reselected.uid_next == mailbox.uid_next => modseq,
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("Could not select folder '{}' again: {}", folder, e);
+                None
+            }
+        });
+
+    result
+}
+
+fn save_eml(
+    dir: &Path,
+    folder: &str,
+    uid_validity: Option<u32>,
+    uid: u32,
+    body: &[u8],
+) -> Result<()> {
+    // The name identifies the message on the server, so saving it again replaces the older copy
+
+    std::fs::create_dir_all(dir)?;
+    let name = format!(
+        "{}_{}_{}.eml",
+        folder.replace(['/', '\\'], "_"),
+        uid_validity.unwrap_or(0),
+        uid
+    );
+    File::create(dir.join(name))?.write_all(body)?;
+    Ok(())
+}
+
+fn process_message(
+    opt: &Opt,
+    mail: &ParsedMail,
+    message_id: &str,
+    manifest: &mut Manifest,
+    summary: &mut Summary,
+) -> Result<(), String> {
+    // Extracts and stores the reports of a single message. Returns the reason if the message
+    // should be tried again later, messages without a report are not an error.
+
+    if opt.trace_mime {
+        log!("MIME structure of message {}:", message_id);
+        trace_mime(mail, 1);
+    }
+
+    if let Some(report) = find_failure_report(mail) {
+        process_forensic(opt, mail, &report, message_id, manifest, summary);
+        return Ok(());
+    }
+
+    let mut attachments = match get_attachments(mail) {
+        Ok(attachments) => attachments,
+        Err(e) => {
+            eprintln!("{} Message: {}", e, message_id);
+            add_error(summary, message_id, e.kind(), e.to_string());
+            return Ok(());
+        }
+    };
+    if opt.multi_attachment == "first" {
+        attachments.truncate(1);
+    }
+
+    if opt.no_decompress {
+        if opt.multi_attachment == "largest" {
+            if let Some(largest) =
+                (0..attachments.len()).max_by_key(|i| attachments[*i].content().len())
+            {
+                attachments = vec![attachments.swap_remove(largest)];
+            }
+        }
+        for attachment in attachments {
+            let stored = match recompress(attachment, &opt.recompress, opt.compression_level) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    eprintln!("{} Message: {}", e, message_id);
+                    add_error(summary, message_id, e.kind(), e.to_string());
+                    continue;
+                }
+            };
+            for (name, content) in stored {
+                match write_output(opt, opt.output_path(), &name, &content, summary) {
+                    Ok(()) => {
+                        summary.reports += 1;
+                        manifest.add(ManifestEntry::new(
+                            &name,
+                            message_id,
+                            None,
+                            None,
+                            content.len(),
+                        ));
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        add_error(summary, message_id, "write", e.to_string());
+                    }
+                };
+            }
+        }
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    let mut decompressed_attachments = Vec::new();
+    for attachment in attachments {
+        match decompress_attachment(attachment) {
+            Ok(decompressed) => decompressed_attachments.push(decompressed),
+            Err(e) => {
+                eprintln!("{} Message: {}", e, message_id);
+                add_error(summary, message_id, e.kind(), e.to_string());
+                result = Err(e.to_string());
+            }
+        }
+    }
+    if opt.multi_attachment == "largest" && !decompressed_attachments.is_empty() {
+        // Other copies that failed to decompress don't matter
+        let size = |attachments: &Vec<Attachment>| -> usize {
+            attachments
+                .iter()
+                .map(|attachment| attachment.decompressed().unwrap_or_default().len())
+                .sum()
+        };
+        let largest = (0..decompressed_attachments.len())
+            .max_by_key(|i| size(&decompressed_attachments[*i]))
+            .unwrap_or_default();
+        decompressed_attachments = vec![decompressed_attachments.swap_remove(largest)];
+        result = Ok(());
+    }
+    // Archives can contain several reports
+    for attachment in decompressed_attachments.into_iter().flatten() {
+        let mut decompressed = attachment.decompressed().unwrap_or_default().to_vec();
+        if opt.strip_bom {
+            let len = strip_bom(&decompressed).len();
+            decompressed.drain(..decompressed.len() - len);
+        }
+
+        if is_tlsrpt(attachment.mimetype(), &decompressed) {
+            process_tlsrpt(
+                opt,
+                attachment.name(),
+                &decompressed,
+                message_id,
+                manifest,
+                summary,
+            );
+            continue;
+        }
+
+        let needs_report = !opt.domains.is_empty()
+            || opt.report_id.is_some()
+            || opt.merge_by_domain
+            || opt.split_by_domain
+            || opt.json_stream
+            || opt.normalize_output
+            || opt.warn_older_than.is_some()
+            || opt.manifest.is_some()
+            || opt.summary_only
+            || opt.coverage
+            || opt.reporters
+            || opt.top_sources.is_some()
+            || opt.check_rules;
+        let mut report = match parse_report(&decompressed) {
+            Ok(report) => Some(report),
+            Err(e) => {
+                if needs_report || opt.verbose {
+                    eprintln!("Could not parse report: {} Message: {}", e, message_id);
+                }
+                if let Some(dir) = opt.quarantine_dir.as_ref().filter(|_| opt.strict) {
+                    let reason = format!("Could not parse report: {}", e);
+                    quarantine(opt, dir, attachment.name(), &decompressed, &reason, summary);
+                    continue;
+                }
+                if !opt.domains.is_empty()
+                    || opt.report_id.is_some()
+                    || opt.merge_by_domain
+                    || opt.summary_only
+                    || opt.strict
+                {
+                    add_error(summary, message_id, "parse", e.to_string());
+                    continue;
+                }
+                None
+            }
+        };
+
+        let mut problems = Vec::new();
+        if opt.check_rules {
+            if let Some(report) = &report {
+                problems.extend(report.check_rules());
+            }
+        }
+        #[cfg(feature = "validate-schema")]
+        if opt.validate_schema {
+            problems.extend(schema::validate(&decompressed));
+        }
+        if !problems.is_empty() {
+            eprintln!(
+                "Report {} violates the rules of the report format: {}. Message: {}",
+                report.as_ref().map_or(attachment.name(), |report| &report
+                    .report_metadata
+                    .report_id),
+                problems.join(", "),
+                message_id
+            );
+            if let Some(dir) = &opt.quarantine_dir {
+                let reason = format!(
+                    "Report violates the rules of the report format: {}",
+                    problems.join(", ")
+                );
+                quarantine(opt, dir, attachment.name(), &decompressed, &reason, summary);
+                continue;
+            }
+            if opt.strict {
+                continue;
+            }
+        }
+
+        if !opt.domains.is_empty() {
+            if let Some(report) = &report {
+                let domain = &report.policy_published.domain;
+                if !opt.domains.iter().any(|d| d.eq_ignore_ascii_case(domain)) {
+                    if opt.verbose {
+                        log!(
+                            "Skipping report for domain {}. Message: {}",
+                            domain,
+                            message_id
+                        );
+                    }
+                    continue;
+                }
+            }
+        }
+
+        if let Some(report_id) = &opt.report_id {
+            if let Some(report) = &report {
+                if report.report_metadata.report_id.trim() != report_id {
+                    if opt.verbose {
+                        log!(
+                            "Skipping report {}. Message: {}",
+                            report.report_metadata.report_id,
+                            message_id
+                        );
+                    }
+                    continue;
+                }
+            }
+        }
+
+        // Only remembered once the report is written, so a failed write is retried in the next run
+        let known = report
+            .as_ref()
+            .and_then(|report| known_report(opt, report, &decompressed));
+        if let Some(known) = &known {
+            if is_known_report(summary, known) {
+                if opt.verbose {
+                    log!(
+                        "Skipping report {}, it was already extracted. Message: {}",
+                        known.0,
+                        message_id
+                    );
+                }
+                summary.duplicates += 1;
+                continue;
+            }
+        }
+
+        if let Some(report) = &report {
+            summary.coverage.add(report);
+            summary.statistics.add(report);
+            summary.reporters.add(report);
+            summary.sources.add(report);
+            check_report_age(opt, report, message_id, summary);
+        }
+
+        if opt.summary_only {
+            if report.is_some() {
+                summary.reports += 1;
+            }
+            continue;
+        }
+
+        if let Some(report) = &mut report {
+            if !opt.dispositions.is_empty() && (opt.json_stream || opt.merge_by_domain) {
+                report.retain_dispositions(&opt.dispositions);
+                if report.records.is_empty() {
+                    if opt.verbose {
+                        log!(
+                            "Report {} has no records with the disposition {}. Message: {}",
+                            report.report_metadata.report_id,
+                            opt.dispositions.join(" or "),
+                            message_id
+                        );
+                    }
+                    continue;
+                }
+            }
+        }
+
+        if opt.json_stream {
+            if let Some(report) = &mut report {
+                summary.enrichment.enrich(report);
+                match serde_json::to_string(report) {
+                    Ok(json) => {
+                        println!("{}", json);
+                        summary.reports += 1;
+                        summary.known_reports.extend(known);
+                    }
+                    Err(e) => {
+                        eprintln!("Could not serialize report: {} Message: {}", e, message_id)
+                    }
+                }
+            }
+            continue;
+        }
+
+        if opt.merge_by_domain {
+            if let Some(mut report) = report {
+                // The state is not saved if the merged reports can't be written
+                merge_report(opt, &mut report, message_id, summary);
+                summary.known_reports.extend(known);
+            }
+            continue;
+        }
+
+        if opt.normalize_output {
+            // Reports that can't be parsed are stored as received
+            if let Some(report) = &mut report {
+                report.normalize();
+                match report.to_xml() {
+                    Ok(xml) => decompressed = xml,
+                    Err(e) => eprintln!(
+                        "Could not normalize report, storing it as received: {} Message: {}",
+                        e, message_id
+                    ),
+                }
+            }
+        }
+
+        let hash = report
+            .as_ref()
+            .map(|report| report_hash(opt, report, &decompressed));
+        let name = match (&report, hash) {
+            (Some(report), Some(hash)) => check_report_id(
+                summary,
+                report,
+                hash,
+                message_id,
+                String::from(attachment.name()),
+            ),
+            _ => String::from(attachment.name()),
+        };
+
+        let (name, content) = if opt.gzip_output {
+            match gzip_report(&name, &decompressed, opt.compression_level) {
+                Ok(compressed) => compressed,
+                Err(e) => {
+                    eprintln!("Could not compress report: {} Message: {}", e, message_id);
+                    add_error(summary, message_id, "compress", e.to_string());
+                    continue;
+                }
+            }
+        } else {
+            (name, decompressed)
+        };
+
+        let dmarc_path = opt.dmarc_path.as_deref().unwrap_or(opt.output_path());
+        let (path, file) = if opt.split_by_domain {
+            let domain = report
+                .as_ref()
+                .map(|report| sanitize_name(&report.policy_published.domain.to_lowercase()))
+                .filter(|domain| !domain.is_empty())
+                .unwrap_or_else(|| String::from(UNKNOWN_DOMAIN_DIR));
+            let path = dmarc_path.join(&domain);
+            if !opt.dry_run {
+                if let Err(e) = std::fs::create_dir_all(&path) {
+                    eprintln!("Could not create {}: {}", path.display(), e);
+                    let reason = format!("Could not create {}: {}", path.display(), e);
+                    add_error(summary, message_id, "write", reason.clone());
+                    result = Err(reason);
+                    continue;
+                }
+            }
+            (path, format!("{}/{}", domain, name))
+        } else {
+            (dmarc_path.to_path_buf(), name.clone())
+        };
+
+        match write_output(opt, &path, &name, &content, summary) {
+            Ok(()) => {
+                summary.reports += 1;
+                summary.known_reports.extend(known);
+                manifest.add(ManifestEntry::new(
+                    &file,
+                    message_id,
+                    report.as_ref(),
+                    hash,
+                    content.len(),
+                ));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                let reason = format!("Could not write {}: {}", name, e);
+                add_error(summary, message_id, "write", reason.clone());
+                result = Err(reason);
+            }
+        };
+    }
+
+    result
+}
+
+fn add_error(summary: &mut Summary, message_id: &str, kind: &str, reason: String) {
+    // The caller adds where the message came from
+
+    summary.errors.push(FailedMessage {
+        message_id: Some(String::from(message_id)),
+        kind: Some(String::from(kind)),
+        reason,
+        ..Default::default()
+    });
+}
+
+fn part_mimetypes(mail: &ParsedMail) -> Vec<String> {
+    // Mimetypes of all parts that are not multipart containers, in their order
+
+    if mail.subparts.is_empty() {
+        return vec![normalize_mimetype(&mail.ctype.mimetype)];
+    }
+    mail.subparts.iter().flat_map(part_mimetypes).collect()
+}
+
+fn message_body<'a>(message: &'a imap::types::Fetch, verbose: bool) -> Option<Cow<'a, [u8]>> {
+    // Some servers answer a fetch of RFC822 with the header and text sections instead of the
+    // whole message
+
+    if let Some(body) = message.body() {
+        return Some(Cow::Borrowed(body));
+    }
+    let text = message.text()?;
+    let uid = message.uid.unwrap_or(0);
+    match message.header() {
+        Some(header) => {
+            if verbose {
+                log!("Using the header and text sections of message {}", uid);
+            }
+            Some(Cow::Owned([header, text].concat()))
+        }
+        None => {
+            if verbose {
+                log!("Using the text section of message {}", uid);
+            }
+            Some(Cow::Borrowed(text))
+        }
+    }
+}
+
+fn fetch_report_part<T: Read + Write>(
+    imap_session: &mut imap::Session<T>,
+    message: &imap::types::Fetch,
+    query: &str,
+    keep_unseen: bool,
+) -> Result<Option<Vec<u8>>, imap::error::Error> {
+    // Fetches the headers and the part with the report, as found in the BODYSTRUCTURE, and puts
+    // them together to a message with only this part. Falls back to the whole message if the part
+    // is not clear, e.g. for single part messages.
+
+    let uid = message.uid.unwrap_or(0).to_string();
+    if let Some(path) = message.bodystructure().and_then(find_report_section) {
+        let section = path
+            .iter()
+            .map(|index| index.to_string())
+            .collect::<Vec<String>>()
+            .join(".");
+        let peek = if keep_unseen { ".PEEK" } else { "" };
+        let fetches = imap_session.uid_fetch(
+            &uid,
+            format!(
+                "(BODY{0}[HEADER] BODY{0}[{1}.MIME] BODY{0}[{1}])",
+                peek, section
+            ),
+        )?;
+        if let Some(fetch) = fetches.iter().next() {
+            let mime = fetch.section(&SectionPath::Part(path.clone(), Some(MessageSection::Mime)));
+            let part = fetch.section(&SectionPath::Part(path, None));
+            if let (Some(header), Some(mime), Some(part)) = (fetch.header(), mime, part) {
+                return Ok(Some(single_part_message(header, mime, part)));
+            }
+        }
+    }
+
+    let fetches = imap_session.uid_fetch(&uid, query)?;
+    Ok(fetches
+        .iter()
+        .next()
+        .and_then(|fetch| fetch.body())
+        .map(|body| body.to_vec()))
+}
+
+fn find_report_section(structure: &BodyStructure) -> Option<Vec<u32>> {
+    // Same as find_report_parts, but only the first part, on the structure reported by the
+    // server. Returns the path of the part, e.g. [2, 1] for section 2.1.
+
+    let bodies = match structure {
+        BodyStructure::Multipart { bodies, .. } => bodies,
+        _ => return None,
+    };
+    bodies.iter().zip(1..).find_map(|(body, index)| match body {
+        BodyStructure::Basic { common, .. } | BodyStructure::Text { common, .. } => {
+            let mimetype = format!("{}/{}", common.ty.ty, common.ty.subtype);
+            if is_usable_mimetype(&normalize_mimetype(&mimetype)) {
+                Some(vec![index])
+            } else {
+                None
+            }
+        }
+        BodyStructure::Multipart { .. } => find_report_section(body).map(|mut path| {
+            path.insert(0, index);
+            path
+        }),
+        BodyStructure::Message { .. } => None,
+    })
+}
+
+fn single_part_message(header: &[u8], mime: &[u8], part: &[u8]) -> Vec<u8> {
+    // Builds a multipart message from the headers of the original message and a single part.
+    // The headers describing the original content are replaced.
+
+    let mut message = Vec::new();
+    if let Ok((headers, _)) = parse_headers(header) {
+        for header in headers {
+            let key = header.get_key_ref();
+            if key.eq_ignore_ascii_case("Content-Type")
+                || key.eq_ignore_ascii_case("Content-Transfer-Encoding")
+            {
+                continue;
+            }
+            message.extend_from_slice(header.get_key_raw());
+            message.extend_from_slice(b": ");
+            message.extend_from_slice(header.get_value_raw());
+            message.extend_from_slice(b"\r\n");
+        }
+    }
+    message.extend_from_slice(
+        format!(
+            "Content-Type: multipart/mixed; boundary=\"{0}\"\r\n\r\n--{0}\r\n",
+            PART_BOUNDARY
+        )
+        .as_bytes(),
+    );
+    message.extend_from_slice(mime);
+    message.extend_from_slice(part);
+    message.extend_from_slice(format!("\r\n--{}--\r\n", PART_BOUNDARY).as_bytes());
+    message
+}
+
+fn filter_by_size(
+    imap_session: &mut ImapSession,
+    opt: &Opt,
+    uids: &[u32],
+    max_size: u32,
+) -> Result<Vec<u32>, imap::error::Error> {
+    // Only fetches the sizes, so messages that are too big are never downloaded
+
+    let mut sizes = Vec::new();
+    for batch in uids.chunks(opt.batch_size.max(1)) {
+        for message in imap_session
+            .uid_fetch(join_uids(batch), "RFC822.SIZE")?
+            .iter()
+        {
+            if let Some(uid) = message.uid {
+                sizes.push((uid, message.size));
+            }
+        }
+    }
+
+    Ok(small_messages(sizes, max_size))
+}
+
+fn small_messages(sizes: Vec<(u32, Option<u32>)>, max_size: u32) -> Vec<u32> {
+    // Returns the sorted UIDs of the messages that may be fetched. Messages the server sent no
+    // size for are kept.
+
+    let mut small = Vec::new();
+    for (uid, size) in sizes {
+        match size {
+            Some(size) if size > max_size => {
+                log!("Skipping message {} with {} bytes", uid, size);
+            }
+            _ => small.push(uid),
+        }
+    }
+    small.sort_unstable();
+    small
+}
+
+fn report_hash(opt: &Opt, report: &Feedback, content: &[u8]) -> u64 {
+    // Hashes the report as received, or in its canonical form with --dedup-mode normalized, so
+    // reports that only differ in formatting are considered the same
+
+    if opt.dedup_mode == "normalized" {
+        let mut report = report.clone();
+        report.normalize();
+        if let Ok(xml) = report.to_xml() {
+            return content_hash(&xml);
+        }
+    }
+    content_hash(content)
+}
+
+fn known_report(opt: &Opt, report: &Feedback, content: &[u8]) -> Option<(String, KnownReport)> {
+    // With --dedupe-window, the entry for the state that remembers the report as extracted
+
+    opt.dedupe_window?;
+    Some((
+        String::from(report.report_metadata.report_id.trim()),
+        KnownReport {
+            hash: format!("{:016x}", report_hash(opt, report, content)),
+            seen: chrono::Utc::now().timestamp(),
+        },
+    ))
+}
+
+fn is_known_report(summary: &Summary, (report_id, report): &(String, KnownReport)) -> bool {
+    // Checks if the report was extracted earlier, in this or a previous run, with the same content
+
+    summary
+        .known_reports
+        .get(report_id)
+        .is_some_and(|known| known.hash == report.hash)
+}
+
+fn write_errors(opt: &Opt, summary: &Summary) -> Result<()> {
+    // Also written with --dry-run, it only describes the run
+
+    match &opt.errors_file {
+        Some(errors_file) => ErrorLog::from(summary.errors.clone()).save(errors_file),
+        None => Ok(()),
+    }
+}
+
+fn merge_report(opt: &Opt, report: &mut Feedback, message_id: &str, summary: &mut Summary) {
+    // Reports sent again, e.g. to several addresses, are only merged once
+
+    let domain = sanitize_name(&report.policy_published.domain.trim().to_lowercase());
+    let domain = if domain.is_empty() {
+        String::from(UNKNOWN_DOMAIN_DIR)
+    } else {
+        domain
+    };
+    let reports = summary.merged.entry(domain).or_default();
+    let report_id = report.report_metadata.report_id.trim();
+    if reports
+        .iter()
+        .any(|merged| merged.report_metadata.report_id.trim() == report_id)
+    {
+        if opt.verbose {
+            log!(
+                "Report {} was already merged. Message: {}",
+                report_id,
+                message_id
+            );
+        }
+        return;
+    }
+    if opt.normalize_output {
+        report.normalize();
+    }
+    summary.enrichment.enrich(report);
+    reports.push(report.clone());
+    summary.reports += 1;
+}
+
+fn write_merged(opt: &Opt, summary: &Summary) -> Result<()> {
+    // Writes the reports collected with --merge-by-domain, once all messages are processed
+
+    let path = opt.dmarc_path.as_deref().unwrap_or(opt.output_path());
+    for (domain, reports) in &summary.merged {
+        let json = serde_json::to_vec_pretty(reports)?;
+        let name = format!("{}.json", domain);
+        write_report(path, opt.temp_dir.as_deref(), &name, &json, opt.dry_run)
+            .map_err(|e| anyhow!("Could not write {}: {}", name, e))?;
+        if opt.verbose {
+            log!(
+                "{} reports for {} written to {}",
+                reports.len(),
+                domain,
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_report_id(
+    summary: &mut Summary,
+    report: &Feedback,
+    hash: u64,
+    message_id: &str,
+    name: String,
+) -> String {
+    // Warns if a report with the same id but different content was seen before in this run and
+    // returns a file name that doesn't overwrite the earlier one
+
+    let report_id = &report.report_metadata.report_id;
+    let seen = match summary.seen_reports.get(report_id) {
+        Some(seen) => seen,
+        None => {
+            summary.seen_reports.insert(
+                report_id.clone(),
+                SeenReport {
+                    hash,
+                    message_id: String::from(message_id),
+                    name: name.clone(),
+                },
+            );
+            return name;
+        }
+    };
+    if seen.hash == hash {
+        return name;
+    }
+
+    eprintln!(
+        "Warning: Report {} was received with different content in message {} and message {}",
+        report_id, seen.message_id, message_id
+    );
+    if seen.name != name {
+        return name;
+    }
+    let path = Path::new(&name);
+    let stem = path
+        .file_stem()
+        .map_or(name.clone(), |stem| stem.to_string_lossy().into_owned());
+    match path.extension() {
+        Some(extension) => format!("{}-{:016x}.{}", stem, hash, extension.to_string_lossy()),
+        None => format!("{}-{:016x}", stem, hash),
+    }
+}
+
+fn process_tlsrpt(
+    opt: &Opt,
+    name: &str,
+    content: &[u8],
+    message_id: &str,
+    manifest: &mut Manifest,
+    summary: &mut Summary,
+) {
+    // Stores an SMTP TLS report, they are not included in the DMARC statistics
+
+    if opt.summary_only || opt.json_stream {
+        return;
+    }
+    if !opt.domains.is_empty() || opt.report_id.is_some() || opt.verbose {
+        match parse_tlsrpt(content) {
+            Ok(report) => {
+                let domains: Vec<&str> = report
+                    .policies
+                    .iter()
+                    .map(|result| result.policy.policy_domain.as_str())
+                    .collect();
+                if !opt.domains.is_empty()
+                    && !domains
+                        .iter()
+                        .any(|domain| opt.domains.iter().any(|d| d.eq_ignore_ascii_case(domain)))
+                {
+                    if opt.verbose {
+                        log!(
+                            "Skipping TLS report for domains {}. Message: {}",
+                            domains.join(", "),
+                            message_id
+                        );
+                    }
+                    return;
+                }
+                if opt
+                    .report_id
+                    .as_ref()
+                    .is_some_and(|report_id| report.report_id.trim() != report_id)
+                {
+                    if opt.verbose {
+                        log!(
+                            "Skipping TLS report {}. Message: {}",
+                            report.report_id,
+                            message_id
+                        );
+                    }
+                    return;
+                }
+                if opt.verbose {
+                    log!(
+                        "TLS report {} from {} for {} - {}",
+                        report.report_id,
+                        report.organization_name,
+                        report.date_range.start_datetime,
+                        report.date_range.end_datetime
+                    );
+                    for result in &report.policies {
+                        log!(
+                            "  {} ({}): {} successful, {} failed sessions",
+                            result.policy.policy_domain,
+                            result.policy.policy_type,
+                            result.summary.total_successful_session_count,
+                            result.summary.total_failure_session_count
+                        );
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not parse TLS report: {} Message: {}", e, message_id);
+                if !opt.domains.is_empty() || opt.report_id.is_some() {
+                    return;
+                }
+            }
+        }
+    }
+
+    let path = opt.tlsrpt_path.as_deref().unwrap_or(opt.output_path());
+    match write_output(opt, path, name, content, summary) {
+        Ok(()) => {
+            summary.tls_reports += 1;
+            manifest.add(ManifestEntry::new(
+                name,
+                message_id,
+                None,
+                None,
+                content.len(),
+            ));
+        }
+        Err(e) => eprintln!("{}", e),
+    };
+}
+
+fn print_config(opt: &Opt, server: &str, port: u16) {
+    // Prints the effective configuration, the password is never shown
+
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    let folders = if opt.auto_folder {
+        String::from("first folder containing 'dmarc', else INBOX")
+    } else if opt.folders.is_empty() {
+        String::from("INBOX")
+    } else {
+        opt.folders.join(", ")
+    };
+    let search = if opt.since_uid.is_some() {
+        "messages from the given UID on"
+    } else if opt.incremental {
+        "messages since the last successful run"
+    } else if opt.only_new_since_last_run {
+        "messages newer than the newest Date of the last run"
+    } else if opt.state.is_some() {
+        "messages after the last processed UID"
+    } else {
+        "all messages"
+    };
+    let search = match opt.since_days {
+        Some(days) => format!("{}, received today or in the {} days before", search, days),
+        None => String::from(search),
+    };
+    let search = if opt.only_unseen {
+        format!("{}, unseen only", search)
+    } else {
+        search
+    };
+
+    let credential = std::env::var_os("CREDENTIALS_DIRECTORY")
+        .map(|credentials| Path::new(&credentials).join(CREDENTIAL_NAME));
+    let password = if opt.password.is_some() {
+        "given (hidden)"
+    } else if credential.is_some_and(|credential| credential.exists()) {
+        "from the systemd credentials"
+    } else {
+        "prompted"
+    };
+    #[cfg(feature = "keyring")]
+    let password = match &opt.keyring {
+        Some(_) if opt.store_password => "prompted, stored in the keyring",
+        Some(_) => "from the keyring",
+        None => password,
+    };
+    #[cfg(unix)]
+    let password = match opt.password_fd {
+        Some(_) => "read from a file descriptor",
+        None => password,
+    };
+
+    log!("Server:          {}", server);
+    log!("Port:            {}", port);
+    log!(
+        "Account:         {}",
+        opt.account.as_deref().unwrap_or_default()
+    );
+    log!("Password:        {}", password);
+    log!(
+        "TLS:             implicit{}",
+        match (&opt.client_cert, &opt.client_key) {
+            (Some(cert), Some(key)) => format!(
+                ", client certificate {} with key {}",
+                cert.display(),
+                key.display()
+            ),
+            (Some(cert), None) => format!(", client certificate {}", cert.display()),
+            _ => String::new(),
+        }
+    );
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    if let Some(days) = opt.tls_expired_grace {
+        log!("Expired cert:    accepted up to {} days after expiry", days);
+    }
+    log!("Folders:         {}", folders);
+    log!("Search:          {}", search);
+    if let Some(state) = &opt.state {
+        log!("State file:      {}", state.display());
+    }
+    if let Some(replay_dir) = &opt.replay_dir {
+        log!("Replay from:     {}", replay_dir.display());
+    }
+    if let Some(save_eml) = &opt.save_eml {
+        log!("Save messages:   {}", save_eml.display());
+    }
+    if opt.output_stdout {
+        log!("Output:          stdout");
+    } else {
+        log!("Output path:     {}", opt.output_path().display());
+    }
+    if let Some(dmarc_path) = &opt.dmarc_path {
+        log!("DMARC path:      {}", dmarc_path.display());
+    }
+    if let Some(tlsrpt_path) = &opt.tlsrpt_path {
+        log!("TLS report path: {}", tlsrpt_path.display());
+    }
+    if let Some(forensic_path) = &opt.forensic_path {
+        log!("Forensic path:   {}", forensic_path.display());
+    }
+    log!(
+        "Format:          {}",
+        if opt.no_decompress && opt.recompress == "gzip" {
+            "gzip, recompressed"
+        } else if opt.no_decompress {
+            "as received"
+        } else if opt.gzip_output {
+            "gzip"
+        } else {
+            "decompressed"
+        }
+    );
+    if !opt.domains.is_empty() {
+        log!("Domains:         {}", opt.domains.join(", "));
+    }
+    if let Some(limit) = opt.limit {
+        log!("Limit:           {} messages", limit);
+    }
+    if let Some(max) = opt.max_messages_per_folder {
+        log!("Per folder:      {} messages", max);
+    }
+    if let Some(manifest) = &opt.manifest {
+        log!("Manifest:        {}", manifest.display());
+    }
+    #[cfg(unix)]
+    let lookups = [
+        ("host names", opt.resolve_ptr),
+        ("GeoIP", !opt.geoip_dbs.is_empty()),
+    ];
+    #[cfg(not(unix))]
+    let lookups = [("GeoIP", !opt.geoip_dbs.is_empty())];
+    let lookups: Vec<&str> = lookups
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    if !lookups.is_empty() {
+        log!("Lookups:         {}", lookups.join(", "));
+    }
+    log!("Batch size:      {}", opt.batch_size);
+    log!("Reconnects:      {}", opt.max_reconnects);
+    log!(
+        "Rule checks:     {}",
+        match (opt.checks_reports(), opt.strict) {
+            (true, true) => "strict",
+            (true, false) => "warn",
+            _ => "no",
+        }
+    );
+    log!("Summary only:    {}", yes_no(opt.summary_only));
+    log!("Coverage:        {}", yes_no(opt.coverage));
+    log!("Dry run:         {}", yes_no(opt.dry_run));
+    log!("All messages:    {}", yes_no(opt.all));
+    #[cfg(feature = "validate-schema")]
+    log!("XSD validation:  {}", yes_no(opt.validate_schema));
+    log!("7z support:      {}", yes_no(cfg!(feature = "seven-z")));
+}
+
+fn get_password(opt: &Opt) -> Result<String> {
+    // Takes the password from the command line, a file descriptor, the keyring or the
+    // credentials of the systemd service, in this order, otherwise prompts for it
+
+    if let Some(password) = &opt.password {
+        return Ok(password.clone());
+    }
+
+    #[cfg(unix)]
+    if let Some(fd) = opt.password_fd {
+        use std::os::unix::io::FromRawFd;
+        // The descriptor is handed over by the caller and not used anywhere else
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let mut password = String::new();
+        file.read_to_string(&mut password)?;
+        return Ok(String::from(password.trim_end_matches(&['\r', '\n'][..])));
+    }
+
+    #[cfg(feature = "keyring")]
+    if let Some(service) = &opt.keyring {
+        let entry = keyring::Entry::new(service, opt.account.as_deref().unwrap_or_default())?;
+        if opt.store_password {
+            let password = rpassword::prompt_password_stderr("Password: ")?;
+            entry.set_password(&password)?;
+            log!("Password stored in the keyring for service '{}'", service);
+            return Ok(password);
+        }
+        return Ok(entry.get_password()?);
+    }
+
+    if let Some(credentials) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+        let path = Path::new(&credentials).join(CREDENTIAL_NAME);
+        if path.exists() {
+            let password = std::fs::read_to_string(path)?;
+            return Ok(String::from(password.trim_end_matches(&['\r', '\n'][..])));
+        }
+    }
+
+    Ok(rpassword::prompt_password_stderr("Password: ")?)
+}
+
+fn open_session(
+    server: &str,
+    port: u16,
+    tls: &Connector,
+    account: &str,
+    password: &str,
+    compress: bool,
+    connect_timeout: Duration,
+) -> Result<(SharedStream, ImapSession)> {
+    let stream = connect(server, port, tls, connect_timeout)?;
+    let mut client = imap::Client::new(stream.clone());
+    client.read_greeting()?;
+    let imap_session = login(stream.clone(), account, password, compress)?;
+
+    Ok((stream, imap_session))
+}
+
+fn login(
+    stream: SharedStream,
+    account: &str,
+    password: &str,
+    compress: bool,
+) -> Result<ImapSession> {
+    // Logs in on a connection that already got the greeting, a new one or a reused one
+
+    let client = imap::Client::new(stream.clone());
+    let mut imap_session = client
+        .login(account, password)
+        .map_err(|(e, _)| login_error(e))?;
+
+    // Compression stays enabled on a reused connection
+    if compress && !stream.is_compressed() {
+        if imap_session.capabilities()?.has_str("COMPRESS=DEFLATE") {
+            stream.enable_compression()?;
+            log!("Compression enabled");
+        } else {
+            log!("Server does not support compression");
+        }
+    }
+
+    Ok(imap_session)
+}
+
+fn login_error(error: imap::error::Error) -> anyhow::Error {
+    // A NO response means the credentials are wrong, other errors are worth another try
+
+    match error {
+        imap::error::Error::No(message) => anyhow::Error::new(LoginRejected(message)),
+        e => anyhow::Error::new(e),
+    }
+}
+
+fn is_connection_error(error: &imap::error::Error) -> bool {
+    matches!(
+        error,
+        imap::error::Error::Io(_) | imap::error::Error::ConnectionLost
+    )
+}
+
+fn connect(server: &str, port: u16, tls: &Connector, timeout: Duration) -> Result<SharedStream> {
+    // Tries all addresses of the server, each with the timeout
+
+    let mut error = None;
+    let mut tcp = None;
+    for address in (server, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                tcp = Some(stream);
+                break;
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    let tcp = match (tcp, error) {
+        (Some(tcp), _) => tcp,
+        (None, Some(e)) => return Err(anyhow!("Could not connect to {}: {}", server, e)),
+        (None, None) => return Err(anyhow!("Could not resolve {}", server)),
+    };
+    tls.connect(server, tcp)
+}
+
+fn get_personal_namespace(stream: &SharedStream) -> Option<String> {
+    // Asks the server for the prefix of the personal namespace, e.g. "INBOX."
+    // Response: * NAMESPACE (("INBOX." ".")) NIL NIL
+
+    let response = stream.raw_command("NAMESPACE").ok()?;
+    let line = response
+        .iter()
+        .find(|line| line.starts_with("* NAMESPACE"))?;
+    let personal = line["* NAMESPACE".len()..]
+        .trim_start()
+        .strip_prefix("((\"")?;
+    let prefix = &personal[..personal.find('"')?];
+
+    if prefix.is_empty() {
+        None
+    } else {
+        Some(String::from(prefix))
+    }
+}
+
+fn sort_by_server(stream: &SharedStream, uids: &[u32]) -> Option<Vec<u32>> {
+    // Lets the server order the messages by their Date header, newest first (RFC 5256). The
+    // response can't be parsed by the imap crate. The UIDs must be sorted.
+    // Response: * SORT 7 3 5
+
+    let command = match sort_command(uids) {
+        Some(command) => command,
+        None => return Some(Vec::new()),
+    };
+    let response = match stream.raw_command(&command) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Could not sort the messages on the server: {}", e);
+            return None;
+        }
+    };
+    Some(parse_sort_response(&response, uids))
+}
+
+fn sort_command(uids: &[u32]) -> Option<String> {
+    // Asks for the range of the UIDs instead of listing each of them, the command line would
+    // exceed the line length limit of the server on large folders. The UIDs in the range that
+    // were not asked for are dropped from the response.
+
+    let (first, last) = (uids.first()?, uids.last()?);
+    Some(format!(
+        "UID SORT (REVERSE DATE) UTF-8 UID {}:{}",
+        first, last
+    ))
+}
+
+fn parse_sort_response(response: &[String], uids: &[u32]) -> Vec<u32> {
+    // Keeps the order of the server, UIDs that were not asked for are dropped. The UIDs must be
+    // sorted.
+
+    response
+        .iter()
+        .filter_map(|line| line.strip_prefix("* SORT"))
+        .flat_map(|line| line.split_whitespace())
+        .filter_map(|uid| uid.parse().ok())
+        .filter(|uid| uids.binary_search(uid).is_ok())
+        .collect()
+}
+
+fn sort_newest_first(
+    imap_session: &mut ImapSession,
+    opt: &Opt,
+    uids: &[u32],
+) -> Result<Vec<u32>, imap::error::Error> {
+    // Orders the messages by their Date header, newest first, for servers without SORT. Only the
+    // headers are fetched. Messages without a parsable Date header come last.
+
+    let mut dates = Vec::new();
+    for batch in uids.chunks(opt.batch_size.max(1)) {
+        for message in imap_session
+            .uid_fetch(join_uids(batch), "BODY.PEEK[HEADER]")?
+            .iter()
+        {
+            let uid = match message.uid {
+                Some(uid) => uid,
+                None => continue,
+            };
+            let date = message
+                .header()
+                .and_then(|header| parse_headers(header).ok())
+                .and_then(|(headers, _)| headers.get_first_value("Date"))
+                .and_then(|date| dateparse(date.trim()).ok());
+            dates.push((date, uid));
+        }
+    }
+    Ok(order_newest_first(dates))
+}
+
+fn order_newest_first(mut dates: Vec<(Option<i64>, u32)>) -> Vec<u32> {
+    // Sorts by the Date, then by UID for the same Date. None sorts before any Some, so the
+    // messages without a Date come last.
+
+    dates.sort_unstable_by(|a, b| b.cmp(a));
+    dates.into_iter().map(|(_, uid)| uid).collect()
+}
+
+fn select_folder(
+    imap_session: &mut ImapSession,
+    folder: &str,
+    namespace: Option<&str>,
+) -> Result<(String, imap::types::Mailbox, Option<u64>)> {
+    // Selects the folder, retrying with the personal namespace prefix if it doesn't exist
+
+    match select_mailbox(imap_session, folder) {
+        Ok((mailbox, modseq)) => Ok((String::from(folder), mailbox, modseq)),
+        Err(imap::error::Error::No(e)) => match namespace {
+            Some(prefix) if !folder.starts_with(prefix) => {
+                let prefixed = format!("{}{}", prefix, folder);
+                let (mailbox, modseq) = select_mailbox(imap_session, &prefixed)?;
+                Ok((prefixed, mailbox, modseq))
+            }
+            _ => Err(anyhow!("{}", e)),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn select_mailbox(
+    imap_session: &mut ImapSession,
+    folder: &str,
+) -> Result<(imap::types::Mailbox, Option<u64>), imap::error::Error> {
+    // Sends the SELECT itself, as the imap crate drops the HIGHESTMODSEQ of the response. Servers
+    // with CONDSTORE or QRESYNC include it (RFC 7162).
+
+    let quoted = folder.replace('\\', "\\\\").replace('"', "\\\"");
+    let response = imap_session.run_command_and_read_response(format!("SELECT \"{}\"", quoted))?;
+    Ok(parse_select_response(&response))
+}
+
+fn parse_select_response(mut lines: &[u8]) -> (imap::types::Mailbox, Option<u64>) {
+    // Reads the untagged responses of a SELECT, lines that can't be parsed are skipped
+    // Response: * 3 EXISTS, * OK [UIDVALIDITY 1], * OK [HIGHESTMODSEQ 4711], ...
+
+    use imap_proto::{MailboxDatum, Response, ResponseCode};
+
+    let mut mailbox = imap::types::Mailbox::default();
+    let mut highest_modseq = None;
+    while !lines.is_empty() {
+        let response = match imap_proto::parse_response(lines) {
+            Ok((rest, response)) => {
+                lines = rest;
+                response
+            }
+            Err(_) => {
+                let end = lines
+                    .iter()
+                    .position(|byte| *byte == b'\n')
+                    .map_or(lines.len(), |end| end + 1);
+                lines = &lines[end..];
+                continue;
+            }
+        };
+        match response {
+            Response::Data {
+                code: Some(code), ..
+            } => match code {
+                ResponseCode::UidValidity(uid_validity) => {
+                    mailbox.uid_validity = Some(uid_validity)
+                }
+                ResponseCode::UidNext(uid_next) => mailbox.uid_next = Some(uid_next),
+                ResponseCode::Unseen(unseen) => mailbox.unseen = Some(unseen),
+                ResponseCode::HighestModSeq(modseq) => highest_modseq = Some(modseq),
+                _ => (),
+            },
+            Response::MailboxData(MailboxDatum::Exists(exists)) => mailbox.exists = exists,
+            Response::MailboxData(MailboxDatum::Recent(recent)) => mailbox.recent = recent,
+            _ => (),
+        }
+    }
+    (mailbox, highest_modseq)
+}
+
+fn find_dmarc_folder(imap_session: &mut ImapSession) -> String {
+    // Looks for a folder with "dmarc" in its name, falls back to the INBOX
+
+    let names = match imap_session.list(None, Some("*")) {
+        Ok(names) => names,
+        Err(e) => {
+            eprintln!("Warning: Could not list folders, using INBOX: {}", e);
+            return String::from("INBOX");
+        }
+    };
+    let mut folders: Vec<&str> = names
+        .iter()
+        .map(|name| name.name())
+        .filter(|name| name.to_lowercase().contains("dmarc"))
+        .collect();
+    folders.sort_unstable();
+
+    match folders.first() {
+        Some(folder) => {
+            log!("Auto-selected folder '{}'", folder);
+            String::from(*folder)
+        }
+        None => {
+            eprintln!("Warning: No folder containing 'dmarc' found, using INBOX");
+            String::from("INBOX")
+        }
+    }
+}
+
+fn write_output(
+    opt: &Opt,
+    path: &Path,
+    name: &str,
+    content: &[u8],
+    summary: &mut Summary,
+) -> Result<()> {
+    // Writes the report to a file in the path, or to stdout with --output-stdout
+
+    if opt.diff {
+        return diff_report(path, name, content, summary);
+    }
+    if !opt.output_stdout {
+        return write_report(path, opt.temp_dir.as_deref(), name, content, opt.dry_run);
+    }
+    if summary.reports + summary.tls_reports + summary.forensic_reports > 0 {
+        // The caller stops the run, the reports found so far are counted as usual
+        summary.too_many_reports = true;
+        return Err(anyhow!(
+            "More than one report found, only one can be written to stdout."
+        ));
+    }
+    if opt.dry_run {
+        log!("Would write {} to stdout", name);
+        return Ok(());
+    }
+    StdoutSink.write_report(name, content)
+}
+
+fn quarantine(
+    opt: &Opt,
+    dir: &Path,
+    name: &str,
+    content: &[u8],
+    reason: &str,
+    summary: &mut Summary,
+) {
+    // Keeps the questionable report for a manual inspection, out of the output path
+
+    match write_quarantined(dir, name, content, reason, opt.dry_run) {
+        Ok(()) => summary.quarantined += 1,
+        Err(e) => eprintln!("Could not quarantine report {}: {}", name, e),
+    }
+}
+
+fn write_quarantined(
+    dir: &Path,
+    name: &str,
+    content: &[u8],
+    reason: &str,
+    dry_run: bool,
+) -> Result<()> {
+    if !dry_run {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_report(dir, None, name, content, dry_run)?;
+    let error = format!("{}\n", reason);
+    write_report(
+        dir,
+        None,
+        &format!("{}.error", name),
+        error.as_bytes(),
+        dry_run,
+    )
+}
+
+fn write_report(
+    path: &Path,
+    temp_dir: Option<&Path>,
+    name: &str,
+    content: &[u8],
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        log!("Would write {}", path.join(name).display());
+        return Ok(());
+    }
+    FileSink::new(path, temp_dir).write_report(name, content)
+}
+
+fn diff_report(path: &Path, name: &str, content: &[u8], summary: &mut Summary) -> Result<()> {
+    // Compares the report with an existing file by their hashes, instead of writing it
+
+    let filepath = path.join(name);
+    let existing = match std::fs::read(&filepath) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log!("Would create {}", filepath.display());
+            summary.would_create += 1;
+            return Ok(());
+        }
+        Err(e) => return Err(anyhow!("Could not read file {}: {}", filepath.display(), e)),
+    };
+    if content_hash(&existing) == content_hash(content) {
+        log!("Unchanged {}", filepath.display());
+        summary.unchanged += 1;
+    } else {
+        log!("Would update {}", filepath.display());
+        summary.would_update += 1;
+    }
+    Ok(())
+}
+
+fn content_hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn check_temp_dir(path: &Path, temp_dir: &Path) {
+    // Warns if the reports can't be moved into place atomically
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if let (Ok(output), Ok(temp)) = (path.metadata(), temp_dir.metadata()) {
+            if output.dev() != temp.dev() {
+                eprintln!(
+                    "Warning: {} is on a different file system than {}, reports will be copied \
+                    instead of moved into place.",
+                    temp_dir.display(),
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+fn gzip_report(name: &str, content: &[u8], level: u32) -> Result<(String, Vec<u8>)> {
+    // Compresses a decompressed report again, the name gets a .gz suffix unless it already has one
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    encoder.write_all(content)?;
+    let compressed = encoder.finish()?;
+
+    let name = if name.to_lowercase().ends_with(".gz") {
+        String::from(name)
+    } else {
+        format!("{}.gz", name)
+    };
+
+    Ok((name, compressed))
+}
+
+fn recompress(
+    attachment: Attachment,
+    mode: &str,
+    level: u32,
+) -> Result<Vec<(String, Vec<u8>)>, ExtractError> {
+    // Returns the files to store for an attachment with --no-decompress: the attachment as
+    // received, or with "gzip" every report in it as <name>.gz, whatever the reporter used
+
+    if mode != "gzip" {
+        return Ok(vec![(attachment.name, attachment.content)]);
+    }
+    let mut stored = Vec::new();
+    for report in decompress_attachment(attachment)? {
+        let compressed = gzip_report(
+            report.name(),
+            report.decompressed().unwrap_or_default(),
+            level,
+        )
+        .map_err(decompression_failed)?;
+        stored.push(compressed);
+    }
+    Ok(stored)
+}
+
+fn since_date(today: chrono::NaiveDate, days: u32) -> chrono::NaiveDate {
+    // The first day of --since-days. Today is searched in addition to the n full days before
+    // it, 0 only searches today.
+
+    today - chrono::Duration::days(days as i64)
+}
+
+fn since_query(since: Option<i64>) -> String {
+    // SEARCH criterion for messages received since the day of the timestamp
+
+    match since.and_then(|since| chrono::DateTime::from_timestamp(since, 0)) {
+        Some(since) => format!(" SINCE {}", since.format("%d-%b-%Y")),
+        None => String::new(),
+    }
+}
+
+fn join_uids(uids: &[u32]) -> String {
+    let uids: Vec<String> = uids.iter().map(u32::to_string).collect();
+    uids.join(",")
+}
+
+fn show_report(file: &Path) -> Result<()> {
+    // Reads a report from disk, decompressing it if needed, and prints its records
+
+    let content = std::fs::read(file)?;
+    let xml = match Format::detect(&content).mimetype() {
+        Some(mimetype) => {
+            let attachments =
+                decompress_attachment(Attachment::new(content, mimetype, &file.to_string_lossy()))?;
+            // Only the first report of an archive is shown
+            attachments
+                .first()
+                .and_then(|attachment| attachment.decompressed())
+                .unwrap_or_default()
+                .to_vec()
+        }
+        None => content,
+    };
+    let report = parse_report(&xml)?;
+    print_report(&report);
+
+    Ok(())
+}
+
+fn self_check(dir: &Path) -> Result<bool> {
+    // Returns whether all good samples passed, bad samples that pass are only reported
+
+    let mut passed = true;
+    for (kind, expected) in [("good", true), ("bad", false)].iter() {
+        let mut samples: Vec<PathBuf> = match std::fs::read_dir(dir.join(kind)) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(anyhow!(
+                    "Could not read {}: {}",
+                    dir.join(kind).display(),
+                    e
+                ))
+            }
+        };
+        samples.sort();
+
+        for sample in samples {
+            let name = format!("{}/{}", kind, sample.file_name().unwrap().to_string_lossy());
+            match (check_sample(&sample), expected) {
+                (Ok(reports), true) => println!("PASS {} ({} reports)", name, reports),
+                (Err(e), true) => {
+                    println!("FAIL {}: {}", name, e);
+                    passed = false;
+                }
+                (Ok(reports), false) => {
+                    println!("FAIL {}: accepted with {} reports", name, reports)
+                }
+                (Err(e), false) => println!("PASS {}: {}", name, e),
+            }
+        }
+    }
+
+    Ok(passed)
+}
+
+fn check_sample(path: &Path) -> Result<usize> {
+    // Runs a sample through the same steps as a report from a mail and returns the number of
+    // reports found in it
+
+    let content = std::fs::read(path)?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let is_mail = Path::new(&name)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("eml"));
+    let received = if is_mail {
+        let mail = parse_mail(&content)?;
+        get_attachments(&mail)?
+    } else {
+        let mimetype = resolve_format(&name, &content)
+            .mimetype()
+            .unwrap_or(XML_MIMETYPE);
+        vec![Attachment::new(content, mimetype, &name)]
+    };
+
+    let mut attachments = Vec::new();
+    for attachment in received {
+        attachments.extend(decompress_attachment(attachment)?);
+    }
+    for attachment in &attachments {
+        let decompressed = attachment.decompressed().unwrap_or_default();
+        if is_tlsrpt(attachment.mimetype(), decompressed) {
+            parse_tlsrpt(decompressed)?;
+        } else {
+            parse_report(decompressed)?;
+        }
+    }
+
+    Ok(attachments.len())
+}
+
+fn print_report(report: &Feedback) {
+    let metadata = &report.report_metadata;
+    println!("Report:    {}", metadata.report_id);
+    println!("Reporter:  {}", metadata.org_name);
+    println!("Domain:    {}", report.policy_published.domain);
+    if let Some(policy) = &report.policy_published.p {
+        println!("Policy:    {}", policy);
+    }
+    println!(
+        "Period:    {} - {}",
+        format_timestamp(metadata.date_range.begin),
+        format_timestamp(metadata.date_range.end)
+    );
+    println!();
+
+    let header = [
+        "Source IP",
+        "Count",
+        "Disposition",
+        "DKIM",
+        "SPF",
+        "Header From",
+    ];
+    let rows: Vec<[String; 6]> = report
+        .records
+        .iter()
+        .map(|record| {
+            let evaluated = &record.row.policy_evaluated;
+            [
+                record.row.source_ip.clone(),
+                record.row.count.to_string(),
+                evaluated.disposition.clone(),
+                evaluated.dkim.clone().unwrap_or_default(),
+                evaluated.spf.clone().unwrap_or_default(),
+                record.identifiers.header_from.clone(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header.to_vec());
+    for row in &rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(date) => date.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+fn get_date(mail: &ParsedMail) -> Option<i64> {
+    // Parses the Date header of the mail into a unix timestamp
+
+    let date = mail.headers.get_first_value("Date")?;
+    dateparse(date.trim()).ok()
+}
+
+fn load_identity(cert: &Path, key: Option<&Path>) -> Result<Identity> {
+    // Loads the client certificate, either from a PEM certificate and key or a PKCS#12 archive
+
+    let cert = std::fs::read(cert)?;
+    let identity = match key {
+        Some(key) => Identity::from_pkcs8(&cert, &std::fs::read(key)?)?,
+        None => {
+            let passphrase = rpassword::prompt_password_stderr("Client certificate passphrase: ")?;
+            Identity::from_pkcs12(&cert, &passphrase)?
+        }
+    };
+
+    Ok(identity)
+}
+
+fn decompress_attachment(mut attachment: Attachment) -> Result<Vec<Attachment>, ExtractError> {
+    // Decompresses the attachment, saves it in te Attachment struct and returns it. A tar
+    // archive results in one attachment for each file in it.
+
+    // The declared mimetype of compressed attachments is not always right, trust the content
+    if [
+        "application/zip",
+        "application/gzip",
+        "application/octet-stream",
+        TAR_MIMETYPE,
+        "application/x-gtar",
+    ]
+    .contains(&attachment.mimetype.as_str())
+    {
+        if let Some(mimetype) = resolve_format(&attachment.name, &attachment.content).mimetype() {
+            attachment.mimetype = String::from(mimetype);
+        } else if is_plain_xml(&attachment.content) {
+            attachment.mimetype = String::from(XML_MIMETYPE);
+        }
+    }
+
+    let content = std::io::Cursor::new(&attachment.content);
+    let mut decompressed: Vec<u8> = Vec::new();
+    match attachment.mimetype.as_str() {
+        "application/zip" => {
+            let mut zip = ZipArchive::new(content).map_err(decompression_failed)?;
+            if zip.is_empty() {
+                return Err(ExtractError::DecompressionFailed(String::from(
+                    "empty zip archive",
+                )));
+            }
+            let mut report = zip.by_index(0).map_err(decompression_failed)?;
+            std::io::copy(&mut report, &mut decompressed).map_err(decompression_failed)?;
+            attachment.name = sanitize_name(report.name());
+        }
+        "application/gzip" | TLSRPT_GZIP_MIMETYPE => {
+            let (content, original_name) = gunzip(&attachment.content, &attachment.name)?;
+            decompressed = content;
+            // The name in the gzip header is the one of the report itself
+            if let Some(original_name) = original_name {
+                attachment.name = original_name;
+            }
+        }
+        #[cfg(feature = "seven-z")]
+        SEVEN_Z_MIMETYPE => {
+            let len = attachment.content.len() as u64;
+            let mut archive =
+                sevenz_rust::SevenZReader::new(content, len, sevenz_rust::Password::empty())
+                    .map_err(decompression_failed)?;
+            let mut name = None;
+            archive
+                .for_each_entries(|entry, report| {
+                    if entry.is_directory() {
+                        return Ok(true);
+                    }
+                    std::io::copy(report, &mut decompressed)?;
+                    name = Some(sanitize_name(entry.name()));
+                    Ok(false)
+                })
+                .map_err(decompression_failed)?;
+            attachment.name = name.ok_or_else(|| {
+                ExtractError::DecompressionFailed(String::from("empty 7z archive"))
+            })?;
+        }
+        XML_MIMETYPE | "text/xml" | TLSRPT_JSON_MIMETYPE | TAR_MIMETYPE => {
+            decompressed = attachment.content.clone()
+        }
+        mimetype => return Err(ExtractError::UnsupportedMime(String::from(mimetype))),
+    }
+    if decompressed.is_empty() {
+        return Err(ExtractError::DecompressionFailed(String::from(
+            "attachment decompressed to nothing",
+        )));
+    }
+    if Format::detect(&decompressed) == Format::Tar {
+        return untar(&decompressed);
+    }
+    attachment.name = output_name(&attachment.name, &decompressed);
+    attachment.decompressed = Some(decompressed);
+
+    Ok(vec![attachment])
+}
+
+fn untar(content: &[u8]) -> Result<Vec<Attachment>, ExtractError> {
+    // Extracts all files of a tar archive, e.g. from a .tar.gz
+
+    let mut attachments = Vec::new();
+    let mut archive = tar::Archive::new(content);
+    for entry in archive.entries().map_err(decompression_failed)? {
+        let mut entry = entry.map_err(decompression_failed)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = sanitize_name(
+            &entry
+                .path()
+                .map_err(decompression_failed)?
+                .to_string_lossy(),
+        );
+        let mut decompressed = Vec::new();
+        entry
+            .read_to_end(&mut decompressed)
+            .map_err(decompression_failed)?;
+        if decompressed.is_empty() {
+            continue;
+        }
+        let mut attachment =
+            Attachment::new(Vec::new(), XML_MIMETYPE, &output_name(&name, &decompressed));
+        attachment.decompressed = Some(decompressed);
+        attachments.push(attachment);
+    }
+    if attachments.is_empty() {
+        return Err(ExtractError::DecompressionFailed(String::from(
+            "empty tar archive",
+        )));
+    }
+
+    Ok(attachments)
+}
+
+fn resolve_format(name: &str, content: &[u8]) -> Format {
+    // The magic bytes are reliable, the file extension is only used for content without them,
+    // e.g. a damaged archive, so it fails with a decompression error instead of being unsupported
+
+    let format = Format::detect(content);
+    if format != Format::Plain || is_plain_xml(content) {
+        return format;
+    }
+    let extension = Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("zip") => Format::Zip,
+        Some("gz") | Some("gzip") | Some("tgz") => Format::Gzip,
+        Some("7z") => Format::SevenZ,
+        _ => Format::Plain,
+    }
+}
+
+fn is_tlsrpt(mimetype: &str, content: &[u8]) -> bool {
+    // SMTP TLS reports are JSON, also when they are sent with a generic mimetype or in an archive
+
+    if mimetype.starts_with("application/tlsrpt") {
+        return true;
+    }
+    let content = strip_bom(content);
+    let start = content
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(content.len());
+    content[start..].starts_with(b"{")
+}
+
+fn is_plain_xml(content: &[u8]) -> bool {
+    // Uncompressed reports sent as application/octet-stream
+
+    let content = strip_bom(content);
+    let start = content
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(content.len());
+    content[start..].starts_with(b"<?xml") || content[start..].starts_with(b"<feedback")
+}
+
+fn output_name(name: &str, content: &[u8]) -> String {
+    // Removes compression extensions and makes sure the extension matches the content, e.g.
+    // "report.xml" that actually was gzip compressed stays "report.xml" instead of "report" and
+    // "example.com.2024" becomes "example.com.2024.xml"
+
+    let mut name = String::from(name);
+    while let Some(extension) = Path::new(&name).extension() {
+        let extension = extension.to_string_lossy().to_lowercase();
+        if !COMPRESSION_EXTENSIONS.contains(&extension.as_str()) {
+            break;
+        }
+        name.truncate(name.len() - extension.len() - 1);
+    }
+
+    // Anything that is not recognizable as JSON is assumed to be an XML report
+    let content = strip_bom(content);
+    let start = content.iter().position(|byte| !byte.is_ascii_whitespace());
+    let extension = match start.map(|start| content[start]) {
+        Some(b'{') => "json",
+        _ => "xml",
+    };
+    let current = Path::new(&name)
+        .extension()
+        .map(|current| current.to_string_lossy().to_lowercase());
+    if current.as_deref() != Some(extension) {
+        name = format!("{}.{}", name, extension);
+    }
+
+    name
+}
+
+fn gunzip(content: &[u8], name: &str) -> Result<(Vec<u8>, Option<String>), ExtractError> {
+    // Returns the decompressed content and the file name from the gzip header (FNAME), if set.
+    // libflate is strict about the gzip header, flate2 accepts some malformed streams as well
+
+    let mut decompressed = Vec::new();
+    let error = match Decoder::new(content) {
+        Ok(mut decoder) => match decoder.read_to_end(&mut decompressed) {
+            Ok(_) => {
+                let original_name = decoder
+                    .header()
+                    .filename()
+                    .and_then(|filename| gzip_filename(filename.as_bytes()));
+                return Ok((decompressed, original_name));
+            }
+            Err(e) => e,
+        },
+        Err(e) => e,
+    };
+
+    decompressed.clear();
+    let mut decoder = flate2::read::GzDecoder::new(content);
+    match decoder.read_to_end(&mut decompressed) {
+        Ok(_) => {
+            log!("Decompressed {} with the fallback gzip decoder", name);
+            let original_name = decoder
+                .header()
+                .and_then(|header| header.filename())
+                .and_then(gzip_filename);
+            Ok((decompressed, original_name))
+        }
+        Err(_) => Err(decompression_failed(error)),
+    }
+}
+
+fn gzip_filename(filename: &[u8]) -> Option<String> {
+    // The name is ISO-8859-1 according to RFC 1952 and may contain a path
+
+    let filename: String = filename.iter().map(|byte| *byte as char).collect();
+    Some(sanitize_name(filename.trim())).filter(|name| !name.is_empty())
+}
+
+fn decompression_failed(e: impl std::fmt::Display) -> ExtractError {
+    ExtractError::DecompressionFailed(e.to_string())
+}
+
+fn trace_mime(mail: &ParsedMail, depth: usize) {
+    // Prints the part and all its subparts, indented by their nesting level
+
+    let content_disposition = mail.get_content_disposition();
+    let disposition = match &content_disposition.disposition {
+        DispositionType::Inline => "inline",
+        DispositionType::Attachment => "attachment",
+        DispositionType::FormData => "form-data",
+        DispositionType::Extension(extension) => extension,
+    };
+    let filename = content_disposition
+        .params
+        .get("filename")
+        .or_else(|| mail.ctype.params.get("name"))
+        .map_or("-", String::as_str);
+    let size = mail.get_body_raw().map_or(0, |body| body.len());
+
+    log!(
+        "{:indent$}{} disposition: {} filename: {} size: {}",
+        "",
+        mail.ctype.mimetype,
+        disposition,
+        filename,
+        size,
+        indent = depth * 2
+    );
+    for subpart in &mail.subparts {
+        trace_mime(subpart, depth + 1);
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    // Only keeps the file name of an archive entry, so it can't point outside the output path
+
+    Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn normalize_mimetype(mimetype: &str) -> String {
+    // Some mailers use unusual casing or leave parameters and whitespace in the mimetype
+
+    let mimetype = mimetype.split(';').next().unwrap_or_default();
+    mimetype.trim().to_lowercase()
+}
+
+fn is_usable_mimetype(mimetype: &str) -> bool {
+    USABLE_MIMETYPES.contains(&mimetype)
+        || (cfg!(feature = "seven-z") && mimetype == SEVEN_Z_MIMETYPE)
+        || (cfg!(feature = "tnef") && mimetype == TNEF_MIMETYPE)
+}
+
+fn find_report_parts<'a>(
+    mail: &'a ParsedMail<'a>,
+    parts: &mut Vec<&'a ParsedMail<'a>>,
+    embedded: &mut Vec<&'a ParsedMail<'a>>,
+) {
+    // Walks the MIME tree depth first, e.g. through multipart/alternative containers, and collects
+    // the parts with a usable mimetype in their order. Text parts are only alternatives to the
+    // report. The messages of a multipart/digest are collected separately, they have to be parsed
+    // on their own. The same goes for a multipart/report, its report-type is not relied on: some
+    // reporters send the report as a bounce (delivery-status) with the report mail attached.
+    // Messages attached to other containers, e.g. forwarded ones, are not searched.
+
+    if is_usable_mimetype(&normalize_mimetype(&mail.ctype.mimetype)) || has_report_extension(mail) {
+        parts.push(mail);
+        return;
+    }
+    let digest = mail.ctype.mimetype.eq_ignore_ascii_case("multipart/digest");
+    let report = mail.ctype.mimetype.eq_ignore_ascii_case("multipart/report");
+    for subpart in &mail.subparts {
+        // The parts of a digest are messages unless they say otherwise
+        if subpart
+            .ctype
+            .mimetype
+            .eq_ignore_ascii_case("message/rfc822")
+            || (digest && subpart.headers.get_first_value("Content-Type").is_none())
+        {
+            if digest || report {
+                embedded.push(subpart);
+            }
+            continue;
+        }
+        find_report_parts(subpart, parts, embedded);
+    }
+}
+
+fn find_failure_report(mail: &ParsedMail) -> Option<FailureReport> {
+    // DMARC failure reports are ARF messages (RFC 6591) with a message/feedback-report part of
+    // the type auth-failure. The other feedback types are not DMARC reports.
+
+    if mail
+        .ctype
+        .mimetype
+        .eq_ignore_ascii_case("message/feedback-report")
+    {
+        let body = mail.get_body_raw().ok()?;
+        let (fields, _) = parse_headers(&body).ok()?;
+        let field = |name: &str| {
+            fields
+                .get_first_value(name)
+                .map(|value| String::from(value.trim()))
+                .unwrap_or_default()
+        };
+        if !field("Feedback-Type").eq_ignore_ascii_case("auth-failure") {
+            return None;
+        }
+        return Some(FailureReport {
+            reported_domain: field("Reported-Domain"),
+            source_ip: field("Source-IP"),
+            auth_failure: field("Auth-Failure"),
+        });
+    }
+    mail.subparts.iter().find_map(find_failure_report)
+}
+
+fn forensic_name(report: &FailureReport, raw: &[u8]) -> String {
+    // Named after the reported domain and source IP, the hash keeps reports of the same source
+    // apart and makes storing the same message again overwrite the first copy
+
+    let clean = |value: &str| {
+        let value: String = value
+            .to_lowercase()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if value.is_empty() {
+            String::from("unknown")
+        } else {
+            value
+        }
+    };
+    format!(
+        "{}!{}!{:016x}.eml",
+        clean(&report.reported_domain),
+        clean(&report.source_ip),
+        content_hash(raw)
+    )
+}
+
+fn process_forensic(
+    opt: &Opt,
+    mail: &ParsedMail,
+    report: &FailureReport,
+    message_id: &str,
+    manifest: &mut Manifest,
+    summary: &mut Summary,
+) {
+    // Stores a DMARC failure report as the whole message, the original message or its headers
+    // are part of it
+
+    if opt.summary_only || opt.json_stream || opt.merge_by_domain || opt.report_id.is_some() {
+        return;
+    }
+    if !opt.domains.is_empty()
+        && !opt
+            .domains
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(&report.reported_domain))
+    {
+        if opt.verbose {
+            log!(
+                "Skipping failure report for domain {}. Message: {}",
+                report.reported_domain,
+                message_id
+            );
+        }
+        return;
+    }
+    if opt.verbose {
+        log!(
+            "Failure report for {} from {}, failed: {}. Message: {}",
+            report.reported_domain,
+            report.source_ip,
+            report.auth_failure,
+            message_id
+        );
+    }
+
+    let name = forensic_name(report, mail.raw_bytes);
+    let path = opt.forensic_path.as_deref().unwrap_or(opt.output_path());
+    match write_output(opt, path, &name, mail.raw_bytes, summary) {
+        Ok(()) => {
+            summary.forensic_reports += 1;
+            manifest.add(ManifestEntry::new(
+                &name,
+                message_id,
+                None,
+                None,
+                mail.raw_bytes.len(),
+            ));
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            add_error(summary, message_id, "write", e.to_string());
+        }
+    };
+}
+
+fn has_report_extension(part: &ParsedMail) -> bool {
+    // Some reporters label compressed reports as e.g. text/plain, only the name tells them apart
+
+    if !part.subparts.is_empty() {
+        return false;
+    }
+    let name = match part_filename(part) {
+        Some(name) => name,
+        None => return false,
+    };
+    Path::new(&name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| {
+            extension == "xml" || COMPRESSION_EXTENSIONS.contains(&extension.as_str())
+        })
+}
+
+fn part_filename(part: &ParsedMail) -> Option<String> {
+    // The disposition type doesn't matter, some reporters mark the report as inline or leave out
+    // the disposition and only name it in the Content-Type. Directories in the name are dropped,
+    // the name is used for the output file.
+
+    let disposition = part.get_content_disposition();
+    let name = |name: Option<&String>| {
+        name.map(|name| sanitize_name(name))
+            .filter(|name| !name.is_empty())
+    };
+    name(disposition.params.get("filename")).or_else(|| name(part.ctype.params.get("name")))
+}
+
+fn get_attachments(mail: &ParsedMail) -> Result<Vec<Attachment>, ExtractError> {
+    // Extracts all attachments with a report from the mail. Fails only if none of them is usable.
+
+    get_embedded_attachments(mail, 0)
+}
+
+fn get_embedded_attachments(
+    mail: &ParsedMail,
+    depth: usize,
+) -> Result<Vec<Attachment>, ExtractError> {
+    // Same as get_attachments, including the reports of the messages bundled in a digest or
+    // attached to a multipart/report
+
+    let mut parts = Vec::new();
+    let mut embedded = Vec::new();
+    find_report_parts(mail, &mut parts, &mut embedded);
+    let mut attachments = Vec::new();
+    let mut error = ExtractError::NoAttachment;
+    for part in parts {
+        match get_attachment(mail, part) {
+            Ok(attachment) => attachments.push(attachment),
+            Err(e) => error = e,
+        }
+    }
+    if depth < MAX_EMBEDDED_DEPTH {
+        for part in embedded {
+            let result = part
+                .get_body_raw()
+                .map_err(ExtractError::from)
+                .and_then(|body| {
+                    let message = parse_mail(&body)?;
+                    get_embedded_attachments(&message, depth + 1)
+                });
+            match result {
+                Ok(found) => attachments.extend(found),
+                Err(e) => error = e,
+            }
+        }
+    }
+    if attachments.is_empty() {
+        return Err(error);
+    }
+    Ok(attachments)
+}
+
+fn part_body(part: &ParsedMail) -> Result<Vec<u8>, ExtractError> {
+    // Some reporters declare base64 or quoted-printable for the raw bytes of a compressed report.
+    // Decoding them would destroy the archive, the magic bytes show that they are not encoded.
+    // Bodies declared as 7bit, 8bit or binary are never decoded.
+
+    match part.get_body_encoded() {
+        Body::Base64(body) | Body::QuotedPrintable(body)
+            if matches!(
+                Format::detect(body.get_raw()),
+                Format::Zip | Format::Gzip | Format::SevenZ
+            ) =>
+        {
+            Ok(body.get_raw().to_vec())
+        }
+        _ => Ok(part.get_body_raw()?),
+    }
+}
+
+fn get_attachment(mail: &ParsedMail, part: &ParsedMail) -> Result<Attachment, ExtractError> {
+    // Extracts the attachment from the part of the mail
+
+    let mut content_type = normalize_mimetype(&part.ctype.mimetype);
+    let body = part_body(part)?;
+    if body.is_empty() {
+        return Err(ExtractError::NoAttachment);
+    }
+    let mut name = part_filename(part);
+    if name.is_none() {
+        // Replaced by the name in a gzip header, if there is one
+        name = mail
+            .headers
+            .get_first_value("Message-ID")
+            .map(|message_id| {
+                let message_id = message_id.trim().trim_start_matches('<');
+                sanitize_name(&message_id.trim_end_matches('>').replace('/', "_"))
+            })
+            .filter(|name| !name.is_empty());
+    }
+    let name = name.ok_or(ExtractError::NoFilename)?;
+
+    if !is_usable_mimetype(&content_type) {
+        // Found by the file name, the format is detected from the content
+        content_type = String::from("application/octet-stream");
+    }
+
+    #[cfg(feature = "tnef")]
+    if content_type == TNEF_MIMETYPE || name.eq_ignore_ascii_case("winmail.dat") {
+        return unpack_tnef(&body);
+    }
+
+    Ok(Attachment::new(body, &content_type, &name))
+}
+
+#[cfg(feature = "tnef")]
+fn unpack_tnef(content: &[u8]) -> Result<Attachment, ExtractError> {
+    // Outlook may wrap the report in a winmail.dat, the format of the attachment in it is
+    // determined by its content
+
+    let attachments = tnef::read_attachments(content).map_err(decompression_failed)?;
+    let attachment = attachments
+        .into_iter()
+        .next()
+        .ok_or(ExtractError::NoAttachment)?;
+    let name = attachment
+        .transport_filename
+        .as_deref()
+        .unwrap_or(&attachment.title);
+    Ok(Attachment::new(
+        attachment.data.to_vec(),
+        "application/octet-stream",
+        &sanitize_name(name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::ffi::OsStr;
+    use std::rc::Rc;
+
+    const REPORT: &[u8] = include_bytes!("../tests/fixtures/report.xml");
+
+    #[test]
+    fn detects_7z_by_magic_bytes() {
+        let archive = include_bytes!("../tests/fixtures/report.7z");
+        assert_eq!(Format::detect(archive), Format::SevenZ);
+        assert_eq!(
+            resolve_format("report.bin", archive).mimetype(),
+            Some(SEVEN_Z_MIMETYPE)
+        );
+    }
+
+    #[cfg(feature = "seven-z")]
+    #[test]
+    fn decompresses_7z_archive() {
+        let archive = include_bytes!("../tests/fixtures/report.7z").to_vec();
+        let attachment = Attachment::new(archive, SEVEN_Z_MIMETYPE, "report.7z");
+        let attachments = decompress_attachment(attachment).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].decompressed(), Some(REPORT));
+        assert_eq!(
+            attachments[0].name(),
+            "example.com!google.com!1700006400!1700092799.xml"
+        );
+    }
+
+    fn zip_archive(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file(name, zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(content).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn recompress_none_keeps_attachment() {
+        let archive = zip_archive("report.xml", REPORT);
+        let attachment = Attachment::new(archive.clone(), "application/zip", "report.zip");
+        let stored = recompress(attachment, "none", 6).unwrap();
+        assert_eq!(stored, vec![(String::from("report.zip"), archive)]);
+    }
+
+    #[test]
+    fn recompress_gzip_replaces_zip() {
+        let archive = zip_archive("report.xml", REPORT);
+        let attachment = Attachment::new(archive, "application/zip", "report.zip");
+        let stored = recompress(attachment, "gzip", 9).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0, "report.xml.gz");
+        let (content, _) = gunzip(&stored[0].1, &stored[0].0).unwrap();
+        assert_eq!(content, REPORT);
+    }
+
+    #[test]
+    fn recompress_gzip_compresses_plain_xml() {
+        let attachment = Attachment::new(REPORT.to_vec(), XML_MIMETYPE, "report.xml");
+        let stored = recompress(attachment, "gzip", 1).unwrap();
+        assert_eq!(stored[0].0, "report.xml.gz");
+        assert_eq!(Format::detect(&stored[0].1), Format::Gzip);
+    }
+
+    #[test]
+    fn names_gzip_report_after_fname() {
+        // The name in the header includes the directory of the reporter
+        let content = include_bytes!("../tests/fixtures/report_fname.xml.gz").to_vec();
+        let attachment = Attachment::new(content, "application/gzip", "attachment.gz");
+        let reports = decompress_attachment(attachment).unwrap();
+        assert_eq!(
+            reports[0].name(),
+            "google.com!example.com!1700006400!1700092799.xml"
+        );
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+
+        let content = include_bytes!("../tests/fixtures/report_no_fname.xml.gz").to_vec();
+        let attachment = Attachment::new(content, "application/gzip", "attachment.gz");
+        let reports = decompress_attachment(attachment).unwrap();
+        assert_eq!(reports[0].name(), "attachment.xml");
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+    }
+
+    #[test]
+    fn falls_back_to_flate2_for_malformed_gzip() {
+        // The extra field of the header doesn't consist of subfields, libflate rejects it
+        let content = include_bytes!("../tests/fixtures/report_extra_field.xml.gz");
+        let mut decompressed = Vec::new();
+        assert!(Decoder::new(&content[..])
+            .and_then(|mut decoder| decoder.read_to_end(&mut decompressed))
+            .is_err());
+        let (decompressed, original_name) = gunzip(content, "report.xml.gz").unwrap();
+        assert_eq!(decompressed, REPORT);
+        assert_eq!(original_name, None);
+    }
+
+    #[test]
+    fn rejects_empty_zip() {
+        let archive = include_bytes!("../tests/fixtures/empty.zip").to_vec();
+        let attachment = Attachment::new(archive, "application/zip", "report.zip");
+        match decompress_attachment(attachment) {
+            Err(ExtractError::DecompressionFailed(reason)) => {
+                assert_eq!(reason, "empty zip archive")
+            }
+            _ => panic!("empty zip archive accepted"),
+        }
+    }
+
+    #[test]
+    fn rejects_attachments_that_decompress_to_nothing() {
+        let fixtures = [
+            (
+                &include_bytes!("../tests/fixtures/empty.xml.gz")[..],
+                "report.xml.gz",
+            ),
+            (
+                &include_bytes!("../tests/fixtures/empty_report.zip")[..],
+                "report.zip",
+            ),
+        ];
+        for (content, name) in fixtures.iter() {
+            let attachment = Attachment::new(content.to_vec(), "application/octet-stream", name);
+            match decompress_attachment(attachment) {
+                Err(ExtractError::DecompressionFailed(reason)) => {
+                    assert_eq!(reason, "attachment decompressed to nothing")
+                }
+                _ => panic!("empty {} accepted", name),
+            }
+        }
+    }
+
+    #[test]
+    fn normalizes_mimetype_casing() {
+        assert_eq!(
+            normalize_mimetype("Application/Zip; name=\"report.zip\""),
+            "application/zip"
+        );
+        assert_eq!(normalize_mimetype(" APPLICATION/GZIP "), "application/gzip");
+    }
+
+    #[test]
+    fn extracts_attachment_with_mimetype_casing() {
+        let mail = include_bytes!("../tests/fixtures/mimetype_casing.eml");
+        let mail = parse_mail(mail).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].mimetype(), "application/zip");
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+    }
+
+    fn gzip_part(encoding: &str, body: &[u8]) -> Vec<u8> {
+        let mut part = format!(
+            "Content-Type: application/gzip\r\nContent-Transfer-Encoding: {}\r\n\r\n",
+            encoding
+        )
+        .into_bytes();
+        part.extend_from_slice(body);
+        part
+    }
+
+    #[test]
+    fn extracts_raw_archive_declared_as_base64() {
+        let mail = include_bytes!("../tests/fixtures/wrong_encoding.eml");
+        let mail = parse_mail(mail).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments.len(), 1);
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+    }
+
+    #[test]
+    fn decodes_base64_archive() {
+        // gzip of "<feedback/>\n"
+        let part = gzip_part(
+            "base64",
+            b"H4sIAAAAAAACA7NJS01NSUpMzta34wIAHUs52AwAAAA=\r\n",
+        );
+        let body = part_body(&parse_mail(&part).unwrap()).unwrap();
+        assert_eq!(Format::detect(&body), Format::Gzip);
+        let mut report = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut report)
+            .unwrap();
+        assert_eq!(report, "<feedback/>\n");
+    }
+
+    #[test]
+    fn keeps_binary_archive_declared_as_7bit() {
+        let archive = gzip(REPORT);
+        for encoding in ["7bit", "8bit", "binary"].iter() {
+            let part = gzip_part(encoding, &archive);
+            assert_eq!(part_body(&parse_mail(&part).unwrap()).unwrap(), archive);
+        }
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn keeps_extensions_that_are_not_compression() {
+        assert_eq!(output_name("report", REPORT), "report.xml");
+        assert_eq!(output_name("mydomain.2024", REPORT), "mydomain.2024.xml");
+        assert_eq!(output_name("report.gz", REPORT), "report.xml");
+        assert_eq!(output_name("report.xml.GZ", REPORT), "report.xml");
+    }
+
+    #[test]
+    fn names_plain_json_after_content() {
+        let json = b"\xef\xbb\xbf\n  {\"organization-name\": \"example.net\"}";
+        assert_eq!(output_name("tlsrpt", json), "tlsrpt.json");
+        assert_eq!(output_name("tlsrpt.JSON", json), "tlsrpt.JSON");
+        assert_eq!(output_name("tlsrpt.json.gz", json), "tlsrpt.json");
+        assert_eq!(
+            output_name("example.net!example.com", json),
+            "example.net!example.com.json"
+        );
+
+        let attachment = Attachment::new(json.to_vec(), TLSRPT_JSON_MIMETYPE, "tlsrpt");
+        let reports = decompress_attachment(attachment).unwrap();
+        assert_eq!(reports[0].name(), "tlsrpt.json");
+        assert_eq!(reports[0].decompressed(), Some(&json[..]));
+    }
+
+    #[test]
+    fn names_gzip_attachment_without_gz_extension() {
+        for (name, expected) in [
+            ("report", "report.xml"),
+            ("mydomain.2024", "mydomain.2024.xml"),
+            ("report.gz", "report.xml"),
+        ] {
+            let attachment = Attachment::new(gzip(REPORT), "application/gzip", name);
+            let reports = decompress_attachment(attachment).unwrap();
+            assert_eq!(reports[0].name(), expected);
+            assert_eq!(reports[0].decompressed(), Some(REPORT));
+        }
+    }
+
+    fn tar_archive(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn resolves_format_of_octet_stream() {
+        let archive = include_bytes!("../tests/fixtures/report.7z");
+        assert_eq!(
+            resolve_format("report.bin", &zip_archive("r.xml", REPORT)),
+            Format::Zip
+        );
+        assert_eq!(resolve_format("report.bin", &gzip(REPORT)), Format::Gzip);
+        assert_eq!(resolve_format("report.bin", archive), Format::SevenZ);
+        assert_eq!(
+            resolve_format("report.bin", &tar_archive("r.xml", REPORT)),
+            Format::Tar
+        );
+        assert_eq!(resolve_format("report.bin", REPORT), Format::Plain);
+        // Plain XML is not mistaken for an archive because of its name
+        assert_eq!(resolve_format("report.zip", REPORT), Format::Plain);
+        // Content without magic bytes falls back to the extension, so it fails to decompress
+        assert_eq!(resolve_format("report.zip", b"broken"), Format::Zip);
+    }
+
+    #[test]
+    fn decompresses_octet_stream_by_content() {
+        for content in [
+            zip_archive("report.xml", REPORT),
+            gzip(REPORT),
+            tar_archive("report.xml", REPORT),
+            REPORT.to_vec(),
+        ] {
+            let attachment = Attachment::new(content, "application/octet-stream", "report.bin");
+            let reports = decompress_attachment(attachment).unwrap();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].decompressed(), Some(REPORT));
+        }
+    }
+
+    #[test]
+    fn failed_write_leaves_report_extractable() {
+        let dir =
+            std::env::temp_dir().join(format!("imap_dmarc_extract-dedupe-{}", std::process::id()));
+        let out = dir.join("out");
+        let opt = Opt::from_iter(&[
+            OsStr::new("imap_dmarc_extract"),
+            OsStr::new("--quiet"),
+            OsStr::new("--dedupe-window"),
+            OsStr::new("30"),
+            OsStr::new("--state"),
+            dir.join("state.json").as_os_str(),
+            OsStr::new("mail.example.com"),
+            OsStr::new("dmarc"),
+            out.as_os_str(),
+        ]);
+        let mail = include_bytes!("../tests/fixtures/mimetype_casing.eml");
+        let mail = parse_mail(mail).unwrap();
+        let mut manifest = Manifest::default();
+        let mut summary = Summary::default();
+
+        // The output path does not exist yet
+        let failed = process_message(&opt, &mail, "<casing@fixture>", &mut manifest, &mut summary);
+        assert!(failed.is_err());
+        assert!(summary.known_reports.is_empty());
+
+        std::fs::create_dir_all(&out).unwrap();
+        let written = process_message(&opt, &mail, "<casing@fixture>", &mut manifest, &mut summary);
+        let duplicate =
+            process_message(&opt, &mail, "<casing@fixture>", &mut manifest, &mut summary);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(written.is_ok() && duplicate.is_ok());
+        assert_eq!(summary.reports, 1);
+        assert_eq!(summary.duplicates, 1);
+        assert!(summary.known_reports.contains_key("11223344556677889900"));
+    }
+
+    #[test]
+    fn saves_tls_report_to_tlsrpt_path() {
+        let dir =
+            std::env::temp_dir().join(format!("imap_dmarc_extract-tlsrpt-{}", std::process::id()));
+        let out = dir.join("out");
+        let tls = dir.join("tls");
+        std::fs::create_dir_all(&out).unwrap();
+        std::fs::create_dir_all(&tls).unwrap();
+        let opt = Opt::from_iter(&[
+            OsStr::new("imap_dmarc_extract"),
+            OsStr::new("--quiet"),
+            OsStr::new("--tlsrpt-path"),
+            tls.as_os_str(),
+            OsStr::new("mail.example.com"),
+            OsStr::new("dmarc"),
+            out.as_os_str(),
+        ]);
+        let mail = parse_mail(include_bytes!("../tests/fixtures/tlsrpt.eml")).unwrap();
+        let mut manifest = Manifest::default();
+        let mut summary = Summary::default();
+
+        let result = process_message(&opt, &mail, "<tlsrpt@fixture>", &mut manifest, &mut summary);
+        let saved = std::fs::read(tls.join("example.net!example.com!1700006400!1700092799.json"));
+        let dmarc_reports = std::fs::read_dir(&out).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+        assert_eq!((summary.tls_reports, summary.reports), (1, 0));
+        assert_eq!(dmarc_reports, 0);
+        let report = parse_tlsrpt(&saved.unwrap()).unwrap();
+        assert_eq!(report.report_id, "2023-11-15T00:00:00Z_example.com");
+        assert_eq!(report.policies[0].summary.total_failure_session_count, 2);
+    }
+
+    #[test]
+    fn writes_all_reports_of_tar_gz() {
+        let dir =
+            std::env::temp_dir().join(format!("imap_dmarc_extract-tar-gz-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let opt = Opt::from_iter(&[
+            OsStr::new("imap_dmarc_extract"),
+            OsStr::new("--quiet"),
+            OsStr::new("mail.example.com"),
+            OsStr::new("dmarc"),
+            dir.as_os_str(),
+        ]);
+        let mail = parse_mail(include_bytes!("../tests/fixtures/reports_tar_gz.eml")).unwrap();
+        let mut manifest = Manifest::default();
+        let mut summary = Summary::default();
+
+        let result = process_message(&opt, &mail, "<tar-gz@fixture>", &mut manifest, &mut summary);
+        let first = std::fs::read(dir.join("example.net!example.com!1700006400!1700092799!1.xml"));
+        let second = std::fs::read(dir.join("example.net!example.com!1700006400!1700092799!2.xml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(summary.reports, 2);
+        assert_eq!(first.unwrap(), REPORT);
+        assert_eq!(
+            second.unwrap(),
+            &include_bytes!("../tests/fixtures/report_full.xml")[..]
+        );
+    }
+
+    fn report_mail(message_id: &str, xml: &[u8]) -> Vec<u8> {
+        let mut mail = format!(
+            "Message-ID: {}\r\nContent-Type: text/xml\r\n\
+             Content-Disposition: attachment; filename=\"report.xml\"\r\n\r\n",
+            message_id
+        )
+        .into_bytes();
+        mail.extend_from_slice(xml);
+        mail
+    }
+
+    #[test]
+    fn keeps_both_reports_with_same_id_and_different_content() {
+        let dir =
+            std::env::temp_dir().join(format!("imap_dmarc_extract-same-id-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let opt = Opt::from_iter(&[
+            OsStr::new("imap_dmarc_extract"),
+            OsStr::new("--quiet"),
+            OsStr::new("mail.example.com"),
+            OsStr::new("dmarc"),
+            dir.as_os_str(),
+        ]);
+        let mut manifest = Manifest::default();
+        let mut summary = Summary::default();
+        let reports = [
+            ("<first@fixture>", REPORT),
+            (
+                "<second@fixture>",
+                &include_bytes!("../tests/fixtures/report_same_id.xml")[..],
+            ),
+        ];
+        for (message_id, xml) in reports.iter() {
+            let mail = report_mail(message_id, xml);
+            let mail = parse_mail(&mail).unwrap();
+            process_message(&opt, &mail, message_id, &mut manifest, &mut summary).unwrap();
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        let first = std::fs::read(dir.join("report.xml")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(summary.reports, 2);
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert_eq!(first, REPORT);
+        assert!(names[0].starts_with("report-") && names[0].ends_with(".xml"));
+    }
+
+    #[test]
+    fn names_reports_with_same_id_after_their_content() {
+        let report = parse_report(REPORT).unwrap();
+        let mut summary = Summary::default();
+        let first = check_report_id(&mut summary, &report, 1, "<a@fixture>", "report.xml".into());
+        let same = check_report_id(&mut summary, &report, 1, "<b@fixture>", "report.xml".into());
+        let other = check_report_id(&mut summary, &report, 2, "<c@fixture>", "report.xml".into());
+        let renamed = check_report_id(&mut summary, &report, 3, "<d@fixture>", "other.xml".into());
+        assert_eq!(first, "report.xml");
+        assert_eq!(same, "report.xml");
+        assert_eq!(other, "report-0000000000000002.xml");
+        assert_eq!(renamed, "other.xml");
+        assert_eq!(
+            summary.seen_reports["11223344556677889900"].message_id,
+            "<a@fixture>"
+        );
+
+        let mut summary = Summary::default();
+        check_report_id(&mut summary, &report, 1, "<a@fixture>", "report".into());
+        let bare = check_report_id(&mut summary, &report, 2, "<b@fixture>", "report".into());
+        assert_eq!(bare, "report-0000000000000002");
+    }
+
+    #[test]
+    fn replays_saved_message() {
+        // A message stored with --save-eml is extracted again without the server, e.g. after an
+        // update added support for its format. Older versions skipped .tar.gz attachments.
+        let dir = std::env::temp_dir().join(format!("imap_dmarc_extract-{}", std::process::id()));
+        let eml_dir = dir.join("eml");
+        let out = dir.join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        let body = include_bytes!("../tests/fixtures/reports_tar_gz.eml");
+        save_eml(&eml_dir, "Reports/DMARC", Some(7), 42, body).unwrap();
+        save_eml(&eml_dir, "Reports/DMARC", Some(7), 42, body).unwrap();
+        let saved: Vec<_> = std::fs::read_dir(&eml_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(saved, ["Reports_DMARC_7_42.eml"]);
+
+        let opt = Opt::from_iter(&[
+            OsStr::new("imap_dmarc_extract"),
+            OsStr::new("--quiet"),
+            OsStr::new("--replay-dir"),
+            eml_dir.as_os_str(),
+            OsStr::new("mail.example.com"),
+            OsStr::new("dmarc"),
+            out.as_os_str(),
+        ]);
+        let code = replay(&opt, &eml_dir).unwrap();
+        let extracted: Vec<_> = std::fs::read_dir(&out)
+            .unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(code, 0);
+        let mut report_ids: Vec<String> = extracted
+            .iter()
+            .map(|xml| parse_report(xml).unwrap().report_metadata.report_id)
+            .collect();
+        report_ids.sort();
+        assert_eq!(report_ids, ["11223344556677889900", "full-1700006400"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_fd_is_not_read_for_each_account() {
+        let result = Opt::from_iter_safe(&[
+            "imap_dmarc_extract",
+            "--accounts",
+            "accounts.json",
+            "--password-fd",
+            "3",
+        ]);
+        assert_eq!(
+            result.unwrap_err().kind,
+            structopt::clap::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn falls_back_to_environment_for_arguments() {
+        // The other tests pass all arguments, so they are not affected by the variables
+        std::env::set_var("IMAP_SERVER", "env.example.com");
+        std::env::set_var("IMAP_ACCOUNT", "env-account");
+        std::env::set_var("IMAP_PATH", "/env/reports");
+        let from_env = Opt::from_iter_safe(&["imap_dmarc_extract"]);
+        let mixed = Opt::from_iter_safe(&["imap_dmarc_extract", "cli.example.com", "cli-account"]);
+        std::env::remove_var("IMAP_SERVER");
+        std::env::remove_var("IMAP_ACCOUNT");
+        std::env::remove_var("IMAP_PATH");
+
+        let from_env = from_env.unwrap();
+        assert_eq!(from_env.server.as_deref(), Some("env.example.com"));
+        assert_eq!(from_env.account.as_deref(), Some("env-account"));
+        assert_eq!(from_env.output_path(), Path::new("/env/reports"));
+        let mixed = mixed.unwrap();
+        assert_eq!(mixed.server.as_deref(), Some("cli.example.com"));
+        assert_eq!(mixed.account.as_deref(), Some("cli-account"));
+        assert_eq!(mixed.output_path(), Path::new("/env/reports"));
+    }
+
+    #[test]
+    fn since_days_crosses_month_and_year() {
+        let date = |y, m, d| chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(since_date(date(2024, 3, 1), 1), date(2024, 2, 29));
+        // A week before, the search covers 8 days in total
+        assert_eq!(since_date(date(2025, 1, 3), 7), date(2024, 12, 27));
+        assert_eq!(since_date(date(2025, 1, 3), 0), date(2025, 1, 3));
+    }
+
+    #[test]
+    fn since_query_uses_imap_date() {
+        let since = since_date(chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(), 1)
+            .and_time(chrono::NaiveTime::MIN)
+            .and_utc()
+            .timestamp();
+        assert_eq!(since_query(Some(since)), " SINCE 29-Feb-2024");
+        assert_eq!(since_query(None), "");
+    }
+
+    #[test]
+    fn finds_report_inside_alternative() {
+        let mail = parse_mail(include_bytes!("../tests/fixtures/alternative.eml")).unwrap();
+        let mut parts = Vec::new();
+        let mut embedded = Vec::new();
+        find_report_parts(&mail, &mut parts, &mut embedded);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].ctype.mimetype, "application/gzip");
+        assert!(embedded.is_empty());
+
+        let attachments = get_attachments(&mail).unwrap();
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        assert_eq!(
+            reports[0].name,
+            "example.net!example.com!1700006400!1700092799.xml"
+        );
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+    }
+
+    /// Answers the commands of an IMAP session with a fixed script
+    #[derive(Debug)]
+    struct ScriptedStream {
+        responses: std::io::Cursor<Vec<u8>>,
+        commands: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.commands.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn scripted_session(
+        responses: Vec<u8>,
+    ) -> (imap::Session<ScriptedStream>, Rc<RefCell<Vec<u8>>>) {
+        // The login is answered first, the responses have to be tagged a2, a3, ...
+
+        let commands = Rc::new(RefCell::new(Vec::new()));
+        let stream = ScriptedStream {
+            responses: std::io::Cursor::new([&b"a1 OK Logged in\r\n"[..], &responses].concat()),
+            commands: commands.clone(),
+        };
+        let session = imap::Client::new(stream).login("user", "secret").unwrap();
+        commands.borrow_mut().clear();
+        (session, commands)
+    }
+
+    fn literal(name: &str, content: &[u8]) -> Vec<u8> {
+        [
+            format!("{} {{{}}}\r\n", name, content.len()).as_bytes(),
+            content,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn fetches_only_report_part() {
+        // The report is section 1.3, inside the multipart/alternative of the message
+        let mail = include_bytes!("../tests/fixtures/alternative.eml");
+        let text = std::str::from_utf8(mail).unwrap();
+        let header_end = text.find("\r\n\r\n").unwrap() + 4;
+        let mime_start = text.find("Content-Type: application/gzip").unwrap();
+        let body_start = mime_start + text[mime_start..].find("\r\n\r\n").unwrap() + 4;
+        let body_end = text.find("\r\n--inner--").unwrap();
+        let (header, mime, body) = (
+            &mail[..header_end],
+            &mail[mime_start..body_start],
+            &mail[body_start..body_end],
+        );
+
+        let structure = b"* 1 FETCH (UID 7 BODYSTRUCTURE (((\"text\" \"plain\" \
+            (\"charset\" \"us-ascii\") NIL NIL \"7bit\" 38 1)(\"text\" \"html\" \
+            (\"charset\" \"us-ascii\") NIL NIL \"7bit\" 45 1)(\"application\" \"gzip\" \
+            (\"name\" \"report.xml.gz\") NIL NIL \"base64\" 700) \"alternative\") \
+            \"mixed\"))\r\na2 OK Fetch completed\r\n";
+        let part = [
+            &b"* 1 FETCH (UID 7 "[..],
+            &literal("BODY[HEADER]", header),
+            b" ",
+            &literal("BODY[1.3.MIME]", mime),
+            b" ",
+            &literal("BODY[1.3]", body),
+            b")\r\na3 OK Fetch completed\r\n",
+        ]
+        .concat();
+        let (mut session, commands) = scripted_session([&structure[..], &part].concat());
+        let fetches = session.uid_fetch("7", "BODYSTRUCTURE").unwrap();
+        assert_eq!(
+            fetches[0].bodystructure().and_then(find_report_section),
+            Some(vec![1, 3])
+        );
+        commands.borrow_mut().clear();
+
+        let message = fetch_report_part(&mut session, &fetches[0], "RFC822", true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&commands.borrow()),
+            "a3 UID FETCH 7 (BODY.PEEK[HEADER] BODY.PEEK[1.3.MIME] BODY.PEEK[1.3])\r\n"
+        );
+        let mail = parse_mail(&message).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Message-ID").as_deref(),
+            Some("<alternative@fixture>")
+        );
+        let attachments = get_attachments(&mail).unwrap();
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+    }
+
+    #[test]
+    fn uses_header_and_text_sections_without_body() {
+        let mail = include_bytes!("../tests/fixtures/mimetype_casing.eml");
+        let text = std::str::from_utf8(mail).unwrap();
+        let (header, body) = mail.split_at(text.find("\r\n\r\n").unwrap() + 4);
+        let response = [
+            &b"* 1 FETCH (UID 7 "[..],
+            &literal("BODY[HEADER]", header),
+            b" ",
+            &literal("BODY[TEXT]", body),
+            b")\r\n* 2 FETCH (UID 8 ",
+            &literal("BODY[TEXT]", b"Only the text\r\n"),
+            b")\r\n* 3 FETCH (UID 9 FLAGS (\\Seen))\r\na2 OK Fetch completed\r\n",
+        ]
+        .concat();
+        let (mut session, _) = scripted_session(response);
+        let fetches = session.uid_fetch("7:9", "RFC822").unwrap();
+        assert!(fetches[0].body().is_none());
+
+        let message = message_body(&fetches[0], false).unwrap();
+        assert_eq!(&message[..], &mail[..]);
+        let mail = parse_mail(&message).unwrap();
+        assert_eq!(get_attachments(&mail).unwrap()[0].name, "report.zip");
+        assert_eq!(
+            message_body(&fetches[1], false).as_deref(),
+            Some(&b"Only the text\r\n"[..])
+        );
+        assert!(message_body(&fetches[2], false).is_none());
+    }
+
+    #[test]
+    fn extracts_zip_labeled_as_text() {
+        let mail = parse_mail(include_bytes!("../tests/fixtures/text_plain_zip.eml")).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        // The text part without a file name is not taken for a report
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].mimetype(), "application/octet-stream");
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        assert_eq!(reports[0].name(), "report.xml");
+        let report = parse_report(reports[0].decompressed().unwrap()).unwrap();
+        assert_eq!(report.report_metadata.report_id, "11223344556677889900");
+    }
+
+    #[test]
+    fn extracts_inline_report() {
+        let mail = parse_mail(include_bytes!("../tests/fixtures/inline.eml")).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(
+            attachments[0].name,
+            "example.net!example.com!1700006400!1700092799.zip"
+        );
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        let report = parse_report(reports[0].decompressed().unwrap()).unwrap();
+        assert_eq!(report.report_metadata.report_id, "11223344556677889900");
+    }
+
+    #[test]
+    fn extracts_report_attached_to_delivery_status() {
+        let mail = include_bytes!("../tests/fixtures/report_delivery_status.eml");
+        let mail = parse_mail(mail).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].mimetype(), "application/zip");
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        let report = parse_report(reports[0].decompressed().unwrap()).unwrap();
+        assert_eq!(report.report_metadata.report_id, "11223344556677889900");
+    }
+
+    #[test]
+    fn decompresses_gzip_named_xml() {
+        // The attachment is called report.xml, but its content is gzip compressed
+        let mail = parse_mail(include_bytes!("../tests/fixtures/gzip_named_xml.eml")).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments[0].name, "report.xml");
+        assert_eq!(Format::detect(attachments[0].content()), Format::Gzip);
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        assert_eq!(reports[0].name, "report.xml");
+        assert_eq!(reports[0].decompressed(), Some(REPORT));
+    }
+
+    #[test]
+    fn sorts_range_of_uids() {
+        let uids: Vec<u32> = (1..=100_000).filter(|uid| uid % 3 != 0).collect();
+        assert_eq!(
+            sort_command(&uids).unwrap(),
+            "UID SORT (REVERSE DATE) UTF-8 UID 1:100000"
+        );
+        assert_eq!(sort_command(&[]), None);
+    }
+
+    #[test]
+    fn keeps_order_of_sort_response() {
+        let response = [
+            String::from("* SORT 7 3 9 5"),
+            String::from("* OK [HIGHESTMODSEQ 12] ignored"),
+        ];
+        // 9 was not asked for, e.g. it arrived after the search
+        assert_eq!(parse_sort_response(&response, &[3, 5, 7]), [7, 3, 5]);
+        assert!(parse_sort_response(&[String::from("* SORT")], &[3]).is_empty());
+    }
+
+    #[test]
+    fn paces_messages() {
+        // 6 messages at 20 per second take at least 5 intervals of 50 ms
+        let start = Instant::now();
+        let mut pacer = Pacer::new(Some(20));
+        for _ in 0..6 {
+            pacer.wait();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        let start = Instant::now();
+        for rate in [None, Some(0)].iter() {
+            let mut pacer = Pacer::new(*rate);
+            for _ in 0..100 {
+                pacer.wait();
+            }
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn skips_oversized_messages() {
+        let sizes = vec![
+            (3, Some(900)),
+            (1, Some(5_000_000)),
+            (2, None),
+            (4, Some(1000)),
+            (5, Some(1001)),
+        ];
+        assert_eq!(small_messages(sizes, 1000), [2, 3, 4]);
+    }
+
+    #[test]
+    fn orders_by_date_newest_first() {
+        let dates = vec![
+            (Some(1700000000), 1),
+            (None, 2),
+            (Some(1700090000), 3),
+            (Some(1700000000), 4),
+        ];
+        assert_eq!(order_newest_first(dates), [3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn extracts_reports_of_digest() {
+        let mail = include_bytes!("../tests/fixtures/digest.eml");
+        let mail = parse_mail(mail).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments.len(), 2);
+        let report_ids: Vec<String> = attachments
+            .into_iter()
+            .flat_map(|attachment| decompress_attachment(attachment).unwrap())
+            .map(|report| {
+                let report = parse_report(report.decompressed().unwrap()).unwrap();
+                report.report_metadata.report_id
+            })
+            .collect();
+        assert_eq!(report_ids, ["11223344556677889900", "99887766554433221100"]);
+    }
+
+    #[test]
+    fn stops_at_nested_digests() {
+        let report = String::from_utf8_lossy(include_bytes!("../tests/fixtures/report.xml"));
+        let mut mail = format!(
+            "Content-Type: application/xml; name=\"report.xml\"\r\n\r\n{}",
+            report
+        );
+        for depth in 0..=MAX_EMBEDDED_DEPTH {
+            let parsed = parse_mail(mail.as_bytes()).unwrap();
+            assert!(get_attachments(&parsed).is_ok(), "{} digests", depth);
+            mail = format!(
+                "Content-Type: multipart/digest; boundary=\"d{0}\"\r\n\r\n--d{0}\r\n\r\n{1}\r\n--d{0}--\r\n",
+                depth, mail
+            );
+        }
+        let parsed = parse_mail(mail.as_bytes()).unwrap();
+        assert!(matches!(
+            get_attachments(&parsed),
+            Err(ExtractError::NoAttachment)
+        ));
+    }
+
+    #[test]
+    fn reads_highest_modseq_of_select() {
+        let response = b"* 3 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen)\r\n\
+            * OK [UIDVALIDITY 7] ok\r\n* OK [UIDNEXT 4] ok\r\n* OK [HIGHESTMODSEQ 4711] ok\r\n";
+        let (mailbox, modseq) = parse_select_response(response);
+        assert_eq!(mailbox.exists, 3);
+        assert_eq!(mailbox.uid_validity, Some(7));
+        assert_eq!(mailbox.uid_next, Some(4));
+        assert_eq!(modseq, Some(4711));
+    }
+
+    #[test]
+    fn select_without_condstore_has_no_modseq() {
+        // The unknown line is skipped, the ones after it are still read
+        let response = b"* 1 EXISTS\r\n* OK [NOMODSEQ] no\r\n* OK [UIDNEXT 2] ok\r\n";
+        let (mailbox, modseq) = parse_select_response(response);
+        assert_eq!(mailbox.uid_next, Some(2));
+        assert_eq!(modseq, None);
+    }
+
+    #[test]
+    fn attachment_name_cannot_leave_output_path() {
+        let mail = "Content-Type: application/gzip; name=\"../../evil.xml.gz\"\r\n\
+            Content-Disposition: attachment; filename=\"..\"\r\n\r\nbody";
+        let mail = parse_mail(mail.as_bytes()).unwrap();
+        assert_eq!(part_filename(&mail).as_deref(), Some("evil.xml.gz"));
+
+        let mail = "Content-Type: application/gzip\r\n\
+            Content-Disposition: attachment; filename=\"/\"\r\n\r\nbody";
+        assert_eq!(part_filename(&parse_mail(mail.as_bytes()).unwrap()), None);
+    }
+
+    #[test]
+    fn no_response_rejects_login() {
+        let error = login_error(imap::error::Error::No(String::from(
+            "[AUTHENTICATIONFAILED]",
+        )));
+        assert!(error.is::<LoginRejected>());
+        assert!(error.to_string().contains("AUTHENTICATIONFAILED"));
+    }
+
+    #[test]
+    fn lost_connection_during_login_is_not_a_rejection() {
+        let error = login_error(imap::error::Error::ConnectionLost);
+        assert!(!error.is::<LoginRejected>());
+        let error = login_error(imap::error::Error::Bad(String::from("syntax")));
+        assert!(!error.is::<LoginRejected>());
+    }
+
+    #[test]
+    fn finds_failure_report() {
+        let raw = include_bytes!("../tests/fixtures/forensic.eml");
+        let mail = parse_mail(raw).unwrap();
+        let report = find_failure_report(&mail).unwrap();
+        assert_eq!(report.reported_domain, "Example.com");
+        assert_eq!(report.auth_failure, "dmarc");
+        let name = forensic_name(&report, raw);
+        assert!(name.starts_with("example.com!192.0.2.1!"));
+        assert!(name.ends_with(".eml"));
+        assert_eq!(name, forensic_name(&report, raw));
+    }
+
+    #[test]
+    fn aggregate_reports_are_not_failure_reports() {
+        let raw = include_bytes!("../tests/fixtures/mimetype_casing.eml");
+        assert!(find_failure_report(&parse_mail(raw).unwrap()).is_none());
+        // Abuse reports use the same format
+        let raw = String::from_utf8_lossy(include_bytes!("../tests/fixtures/forensic.eml"))
+            .replace("Feedback-Type: auth-failure", "Feedback-Type: abuse");
+        assert!(find_failure_report(&parse_mail(raw.as_bytes()).unwrap()).is_none());
+    }
+
+    #[test]
+    fn names_failure_report_without_fields() {
+        let report = FailureReport {
+            reported_domain: String::new(),
+            source_ip: String::from("2001:db8::1"),
+            auth_failure: String::new(),
+        };
+        let name = forensic_name(&report, b"");
+        assert!(name.starts_with("unknown!2001_db8__1!"));
+    }
+
+    #[cfg(feature = "tnef")]
+    #[test]
+    fn extracts_report_from_winmail_dat() {
+        let mail = include_bytes!("../tests/fixtures/winmail.eml");
+        let mail = parse_mail(mail).unwrap();
+        let attachments = get_attachments(&mail).unwrap();
+        assert_eq!(attachments.len(), 1);
+        let reports = decompress_attachment(attachments.into_iter().next().unwrap()).unwrap();
+        let report = parse_report(reports[0].decompressed().unwrap()).unwrap();
+        assert_eq!(report.policy_published.domain, "example.com");
+        assert!(reports[0].name().ends_with(".xml"));
+    }
+
+    #[cfg(not(feature = "seven-z"))]
+    #[test]
+    fn rejects_7z_without_feature() {
+        let archive = include_bytes!("../tests/fixtures/report.7z").to_vec();
+        let attachment = Attachment::new(archive, SEVEN_Z_MIMETYPE, "report.7z");
+        assert!(matches!(
+            decompress_attachment(attachment),
+            Err(ExtractError::UnsupportedMime(_))
+        ));
+    }
 }
@@ -0,0 +1,114 @@
+use crate::report::Feedback;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+
+#[derive(Debug, Deserialize, Serialize)]
+/// Information about one report written to disk
+pub struct ManifestEntry {
+    pub file: String,
+    pub message_id: String,
+    pub report_id: Option<String>,
+    pub org_name: Option<String>,
+    pub domain: Option<String>,
+    pub begin: Option<i64>,
+    pub end: Option<i64>,
+    /// Report period in ISO 8601 format, same as begin and end
+    #[serde(default)]
+    pub begin_utc: Option<String>,
+    #[serde(default)]
+    pub end_utc: Option<String>,
+    /// Hash of the report used to detect differing reports with the same id
+    #[serde(default)]
+    pub hash: Option<String>,
+    pub size: usize,
+}
+
+impl ManifestEntry {
+    pub fn new(
+        file: &str,
+        message_id: &str,
+        report: Option<&Feedback>,
+        hash: Option<u64>,
+        size: usize,
+    ) -> Self {
+        ManifestEntry {
+            file: String::from(file),
+            message_id: String::from(message_id),
+            report_id: report.map(|r| r.report_metadata.report_id.clone()),
+            org_name: report.map(|r| r.report_metadata.org_name.clone()),
+            domain: report.map(|r| r.policy_published.domain.clone()),
+            begin: report.map(|r| r.report_metadata.date_range.begin),
+            end: report.map(|r| r.report_metadata.date_range.end),
+            begin_utc: report.and_then(|r| iso_timestamp(r.report_metadata.date_range.begin)),
+            end_utc: report.and_then(|r| iso_timestamp(r.report_metadata.date_range.end)),
+            hash: hash.map(|hash| format!("{:016x}", hash)),
+            size,
+        }
+    }
+}
+
+fn iso_timestamp(timestamp: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|date| date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+#[derive(Debug, Default)]
+/// Index of all reports written, kept across runs
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest> {
+        // Loads an existing manifest, a missing file results in an empty one
+
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let file = File::open(path)?;
+        Ok(Manifest {
+            entries: serde_json::from_reader(file)?,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &self.entries)?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, entry: ManifestEntry) {
+        // Replaces an existing entry for the same report and file
+
+        if let Some(report_id) = &entry.report_id {
+            self.entries
+                .retain(|e| e.report_id.as_ref() != Some(report_id) || e.file != entry.file);
+        }
+        self.entries.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::parse_report;
+
+    #[test]
+    fn formats_known_epoch() {
+        assert_eq!(iso_timestamp(0).as_deref(), Some("1970-01-01T00:00:00Z"));
+        assert_eq!(
+            iso_timestamp(1700006400).as_deref(),
+            Some("2023-11-15T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn entry_has_report_period_in_utc() {
+        let report = parse_report(include_bytes!("../tests/fixtures/report.xml")).unwrap();
+        let entry = ManifestEntry::new("report.xml", "<id@x>", Some(&report), None, 10);
+        assert_eq!(entry.begin_utc.as_deref(), Some("2023-11-15T00:00:00Z"));
+        assert_eq!(entry.end_utc.as_deref(), Some("2023-11-15T23:59:59Z"));
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Totals since the start, updated by the runs and read by the HTTP server
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+/// Requests with a longer request line and headers are cut off
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// Counters of the runs of --watch, served by the serve command
+pub struct Metrics {
+    pub runs: u64,
+    /// Runs with errors, e.g. the server could not be reached or messages could not be fetched
+    pub failed_runs: u64,
+    pub reports: u64,
+    pub tls_reports: u64,
+    pub forensic_reports: u64,
+    pub failed_messages: u64,
+    /// Messages of the DMARC reports that passed DKIM or SPF, and the other ones
+    pub passed: u64,
+    pub failed: u64,
+    /// Unix timestamp of the start of the last run without errors
+    pub last_success: Option<i64>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            runs: 0,
+            failed_runs: 0,
+            reports: 0,
+            tls_reports: 0,
+            forensic_reports: 0,
+            failed_messages: 0,
+            passed: 0,
+            failed: 0,
+            last_success: None,
+        }
+    }
+
+    fn render(&self) -> String {
+        // Prometheus text exposition format
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            out.push_str(&format!("# HELP imap_dmarc_extract_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE imap_dmarc_extract_{} {}\n", name, kind));
+            for (labels, value) in samples {
+                out.push_str(&format!(
+                    "imap_dmarc_extract_{}{} {}\n",
+                    name, labels, value
+                ));
+            }
+        };
+        metric(
+            "runs_total",
+            "counter",
+            "Runs since the start",
+            &[("", self.runs)],
+        );
+        metric(
+            "failed_runs_total",
+            "counter",
+            "Runs that had errors",
+            &[("", self.failed_runs)],
+        );
+        metric(
+            "reports_total",
+            "counter",
+            "Reports extracted",
+            &[
+                ("{type=\"dmarc\"}", self.reports),
+                ("{type=\"tlsrpt\"}", self.tls_reports),
+                ("{type=\"forensic\"}", self.forensic_reports),
+            ],
+        );
+        metric(
+            "failed_messages_total",
+            "counter",
+            "Messages that could not be fetched or processed",
+            &[("", self.failed_messages)],
+        );
+        metric(
+            "report_messages_total",
+            "counter",
+            "Messages covered by the DMARC reports, by the DMARC result",
+            &[
+                ("{result=\"pass\"}", self.passed),
+                ("{result=\"fail\"}", self.failed),
+            ],
+        );
+        // No sample until the first run succeeded, so an alert can tell it from an old success
+        let last_success: Vec<(&str, u64)> = self
+            .last_success
+            .map(|timestamp| ("", timestamp.max(0) as u64))
+            .into_iter()
+            .collect();
+        metric(
+            "last_success_timestamp_seconds",
+            "gauge",
+            "Start of the last run without errors",
+            &last_success,
+        );
+        out
+    }
+}
+
+pub fn update(f: impl FnOnce(&mut Metrics)) {
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut metrics);
+}
+
+pub fn serve(listen: SocketAddr) -> Result<()> {
+    // Binds right away so a wrong address fails at the start, the requests are answered by a
+    // thread one after the other
+
+    let listener = TcpListener::bind(listen)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(handle);
+            if let Err(e) = result {
+                eprintln!("Could not answer metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are read as well, closing the socket with unread data resets the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let (status, body) = respond(&request_line, &metrics);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    (&stream).write_all(response.as_bytes())
+}
+
+fn respond(request_line: &str, metrics: &Metrics) -> (&'static str, String) {
+    // Returns the status line and the body for the request
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    if method != "GET" {
+        return (
+            "405 Method Not Allowed",
+            String::from("Only GET is supported\n"),
+        );
+    }
+    // Scrapers may add parameters, they are ignored
+    match target.split('?').next() {
+        Some("/metrics") => ("200 OK", metrics.render()),
+        _ => (
+            "404 Not Found",
+            String::from("Metrics are served at /metrics\n"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_last_success() {
+        let metrics = Metrics {
+            runs: 3,
+            failed_runs: 1,
+            reports: 5,
+            passed: 40,
+            failed: 2,
+            last_success: Some(1700000000),
+            ..Metrics::default()
+        };
+        let (status, body) = respond("GET /metrics HTTP/1.1\r\n", &metrics);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("# TYPE imap_dmarc_extract_runs_total counter\n"));
+        assert!(body.contains("imap_dmarc_extract_runs_total 3\n"));
+        assert!(body.contains("imap_dmarc_extract_reports_total{type=\"dmarc\"} 5\n"));
+        assert!(body.contains("imap_dmarc_extract_report_messages_total{result=\"fail\"} 2\n"));
+        assert!(body.contains("imap_dmarc_extract_last_success_timestamp_seconds 1700000000\n"));
+    }
+
+    #[test]
+    fn no_last_success_before_first_successful_run() {
+        let body = Metrics::default().render();
+        assert!(body.contains("# TYPE imap_dmarc_extract_last_success_timestamp_seconds gauge\n"));
+        assert!(!body.contains("\nimap_dmarc_extract_last_success_timestamp_seconds "));
+    }
+
+    #[test]
+    fn only_serves_metrics_path() {
+        let metrics = Metrics::default();
+        assert_eq!(respond("GET /metrics?x=1 HTTP/1.1", &metrics).0, "200 OK");
+        assert_eq!(respond("GET / HTTP/1.1", &metrics).0, "404 Not Found");
+        assert_eq!(
+            respond("POST /metrics HTTP/1.1", &metrics).0,
+            "405 Method Not Allowed"
+        );
+        assert_eq!(respond("", &metrics).0, "405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,578 @@
+use anyhow::Result;
+use encoding_rs::Encoding;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+/// DMARC aggregate report as defined in RFC 7489, appendix C
+pub struct Feedback {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub report_metadata: ReportMetadata,
+    pub policy_published: PolicyPublished,
+    #[serde(rename = "record", default)]
+    pub records: Vec<Record>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ReportMetadata {
+    pub org_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_contact_info: Option<String>,
+    pub report_id: String,
+    pub date_range: DateRange,
+    /// Problems the reporter had while creating the report
+    #[serde(rename = "error", default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DateRange {
+    pub begin: i64,
+    pub end: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PolicyPublished {
+    pub domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adkim: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspf: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pct: Option<u32>,
+    /// Failure reporting options, e.g. "0:d"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fo: Option<String>,
+    /// Policy for non-existent subdomains, from the DMARCbis draft
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub np: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    pub row: Row,
+    pub identifiers: Identifiers,
+    #[serde(default, skip_serializing_if = "AuthResults::is_empty")]
+    pub auth_results: AuthResults,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Row {
+    pub source_ip: String,
+    pub count: u64,
+    pub policy_evaluated: PolicyEvaluated,
+    /// Looked up with --resolve-ptr and --geoip-db, not part of the report
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceInfo>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+/// What is known about a source IP besides the report
+pub struct SourceInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// ISO 3166 code of the country
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_org: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PolicyEvaluated {
+    pub disposition: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dkim: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spf: Option<String>,
+    #[serde(rename = "reason", default, skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<PolicyOverrideReason>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+/// Why the receiver applied another disposition than the published policy
+pub struct PolicyOverrideReason {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Identifiers {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope_from: Option<String>,
+    pub header_from: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+/// Results of the individual DKIM signatures and SPF checks, before applying the DMARC policy
+pub struct AuthResults {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dkim: Vec<DkimAuthResult>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spf: Vec<SpfAuthResult>,
+}
+
+impl AuthResults {
+    pub fn is_empty(&self) -> bool {
+        self.dkim.is_empty() && self.spf.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DkimAuthResult {
+    pub domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+    pub result: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub human_result: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SpfAuthResult {
+    pub domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    pub result: String,
+}
+
+const DISPOSITIONS: [&str; 3] = ["none", "quarantine", "reject"];
+const ALIGNMENTS: [&str; 2] = ["r", "s"];
+const RESULTS: [&str; 2] = ["pass", "fail"];
+const DKIM_RESULTS: [&str; 7] = [
+    "none",
+    "pass",
+    "fail",
+    "policy",
+    "neutral",
+    "temperror",
+    "permerror",
+];
+const SPF_RESULTS: [&str; 7] = [
+    "none",
+    "neutral",
+    "pass",
+    "fail",
+    "softfail",
+    "temperror",
+    "permerror",
+];
+const SPF_SCOPES: [&str; 2] = ["helo", "mfrom"];
+const OVERRIDE_REASONS: [&str; 6] = [
+    "forwarded",
+    "sampled_out",
+    "trusted_forwarder",
+    "mailing_list",
+    "local_policy",
+    "other",
+];
+
+impl Feedback {
+    pub fn check_rules(&self) -> Vec<String> {
+        // Checks required fields and allowed values of the aggregate report format (RFC 7489,
+        // appendix C) that are not already enforced by parsing. Returns the violations found.
+
+        let mut problems = Vec::new();
+        let metadata = &self.report_metadata;
+        if metadata.org_name.trim().is_empty() {
+            problems.push(String::from("org_name is empty"));
+        }
+        if metadata.email.is_none() {
+            problems.push(String::from("email is missing"));
+        }
+        if metadata.report_id.trim().is_empty() {
+            problems.push(String::from("report_id is empty"));
+        }
+        if metadata.date_range.begin > metadata.date_range.end {
+            problems.push(String::from("date_range begins after its end"));
+        }
+
+        let policy = &self.policy_published;
+        if policy.domain.trim().is_empty() {
+            problems.push(String::from("policy_published domain is empty"));
+        }
+        check_value(&mut problems, "p", policy.p.as_deref(), &DISPOSITIONS, true);
+        check_value(
+            &mut problems,
+            "sp",
+            policy.sp.as_deref(),
+            &DISPOSITIONS,
+            false,
+        );
+        check_value(
+            &mut problems,
+            "adkim",
+            policy.adkim.as_deref(),
+            &ALIGNMENTS,
+            false,
+        );
+        check_value(
+            &mut problems,
+            "aspf",
+            policy.aspf.as_deref(),
+            &ALIGNMENTS,
+            false,
+        );
+
+        if self.records.is_empty() {
+            problems.push(String::from("no record"));
+        }
+        for record in &self.records {
+            let row = &record.row;
+            if row.source_ip.trim().parse::<IpAddr>().is_err() {
+                problems.push(format!("source_ip {} is not an IP address", row.source_ip));
+            }
+            let evaluated = &row.policy_evaluated;
+            check_value(
+                &mut problems,
+                "disposition",
+                Some(&evaluated.disposition),
+                &DISPOSITIONS,
+                true,
+            );
+            check_value(
+                &mut problems,
+                "dkim",
+                evaluated.dkim.as_deref(),
+                &RESULTS,
+                true,
+            );
+            check_value(
+                &mut problems,
+                "spf",
+                evaluated.spf.as_deref(),
+                &RESULTS,
+                true,
+            );
+            for reason in &evaluated.reasons {
+                check_value(
+                    &mut problems,
+                    "reason type",
+                    Some(&reason.kind),
+                    &OVERRIDE_REASONS,
+                    true,
+                );
+            }
+            if record.identifiers.header_from.trim().is_empty() {
+                problems.push(String::from("header_from is empty"));
+            }
+            for dkim in &record.auth_results.dkim {
+                check_value(
+                    &mut problems,
+                    "auth_results dkim result",
+                    Some(&dkim.result),
+                    &DKIM_RESULTS,
+                    true,
+                );
+            }
+            for spf in &record.auth_results.spf {
+                check_value(
+                    &mut problems,
+                    "auth_results spf result",
+                    Some(&spf.result),
+                    &SPF_RESULTS,
+                    true,
+                );
+                check_value(
+                    &mut problems,
+                    "auth_results spf scope",
+                    spf.scope.as_deref(),
+                    &SPF_SCOPES,
+                    false,
+                );
+            }
+        }
+
+        problems.dedup();
+        problems
+    }
+
+    /// Keeps only the records with one of the dispositions, e.g. "quarantine" and "reject"
+    pub fn retain_dispositions(&mut self, dispositions: &[String]) {
+        self.records.retain(|record| {
+            let disposition = record.row.policy_evaluated.disposition.trim();
+            dispositions
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(disposition))
+        });
+    }
+
+    pub fn normalize(&mut self) {
+        // Removes surrounding whitespace and sorts the records, so the same report always results
+        // in the same output
+
+        let metadata = &mut self.report_metadata;
+        let policy = &mut self.policy_published;
+        for value in [
+            &mut metadata.org_name,
+            &mut metadata.report_id,
+            &mut policy.domain,
+        ] {
+            trim(value);
+        }
+        for value in [
+            &mut self.version,
+            &mut metadata.email,
+            &mut metadata.extra_contact_info,
+            &mut policy.adkim,
+            &mut policy.aspf,
+            &mut policy.p,
+            &mut policy.sp,
+            &mut policy.fo,
+            &mut policy.np,
+        ]
+        .iter_mut()
+        .filter_map(|value| value.as_mut())
+        {
+            trim(value);
+        }
+        for error in &mut metadata.errors {
+            trim(error);
+        }
+
+        for record in &mut self.records {
+            let row = &mut record.row;
+            let evaluated = &mut row.policy_evaluated;
+            for value in [
+                &mut row.source_ip,
+                &mut evaluated.disposition,
+                &mut record.identifiers.header_from,
+            ] {
+                trim(value);
+            }
+            for value in [
+                &mut evaluated.dkim,
+                &mut evaluated.spf,
+                &mut record.identifiers.envelope_to,
+                &mut record.identifiers.envelope_from,
+            ]
+            .iter_mut()
+            .filter_map(|value| value.as_mut())
+            {
+                trim(value);
+            }
+            for reason in &mut evaluated.reasons {
+                trim(&mut reason.kind);
+                if let Some(comment) = &mut reason.comment {
+                    trim(comment);
+                }
+            }
+            for dkim in &mut record.auth_results.dkim {
+                for value in [&mut dkim.domain, &mut dkim.result] {
+                    trim(value);
+                }
+                for value in [&mut dkim.selector, &mut dkim.human_result]
+                    .iter_mut()
+                    .filter_map(|value| value.as_mut())
+                {
+                    trim(value);
+                }
+            }
+            for spf in &mut record.auth_results.spf {
+                for value in [&mut spf.domain, &mut spf.result] {
+                    trim(value);
+                }
+                if let Some(scope) = &mut spf.scope {
+                    trim(scope);
+                }
+            }
+        }
+        self.records.sort_by(|a, b| {
+            let key = |record: &Record| {
+                (
+                    record.row.source_ip.clone(),
+                    record.identifiers.header_from.clone(),
+                    record.row.policy_evaluated.disposition.clone(),
+                    record.row.policy_evaluated.dkim.clone(),
+                    record.row.policy_evaluated.spf.clone(),
+                    record.row.count,
+                )
+            };
+            key(a).cmp(&key(b))
+        });
+    }
+
+    pub fn to_xml(&self) -> Result<Vec<u8>> {
+        // Serializes the report with a fixed indentation, only the known fields are included
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let mut serializer = quick_xml::se::Serializer::with_root(&mut xml, Some("feedback"))?;
+        serializer.indent(' ', 2);
+        self.serialize(serializer)?;
+        xml.push('\n');
+        Ok(xml.into_bytes())
+    }
+}
+
+fn trim(value: &mut String) {
+    let trimmed = value.trim();
+    if trimmed.len() != value.len() {
+        *value = String::from(trimmed);
+    }
+}
+
+fn check_value(
+    problems: &mut Vec<String>,
+    name: &str,
+    value: Option<&str>,
+    allowed: &[&str],
+    required: bool,
+) {
+    match value {
+        Some(value) if !allowed.contains(&value.trim()) => {
+            problems.push(format!("{} has the invalid value '{}'", name, value))
+        }
+        None if required => problems.push(format!("{} is missing", name)),
+        _ => (),
+    }
+}
+
+pub fn parse_report(xml: &[u8]) -> Result<Feedback> {
+    let xml = to_utf8(strip_bom(xml));
+    Ok(quick_xml::de::from_reader(xml.as_ref())?)
+}
+
+fn to_utf8(xml: &[u8]) -> Cow<'_, [u8]> {
+    // Some reporters declare e.g. ISO-8859-1 in the XML declaration, the parser only reads UTF-8
+
+    match declared_encoding(xml) {
+        Some(encoding) if encoding != encoding_rs::UTF_8 => {
+            let (decoded, _, _) = encoding.decode(xml);
+            Cow::Owned(decoded.into_owned().into_bytes())
+        }
+        _ => Cow::Borrowed(xml),
+    }
+}
+
+fn declared_encoding(xml: &[u8]) -> Option<&'static Encoding> {
+    let declaration = xml.strip_prefix(b"<?xml")?;
+    let end = declaration.windows(2).position(|w| w == b"?>")?;
+    let declaration = String::from_utf8_lossy(&declaration[..end]);
+    let value = declaration.split("encoding").nth(1)?.trim_start();
+    let value = value.strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let label = value[1..].split(quote).next()?;
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+pub fn strip_bom(content: &[u8]) -> &[u8] {
+    content.strip_prefix(b"\xef\xbb\xbf").unwrap_or(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &[u8] = include_bytes!("../tests/fixtures/report.xml");
+    const REPORT_BOM: &[u8] = include_bytes!("../tests/fixtures/report_bom.xml");
+    const REPORT_FULL: &[u8] = include_bytes!("../tests/fixtures/report_full.xml");
+    const REPORT_INTERLEAVED: &[u8] = include_bytes!("../tests/fixtures/report_interleaved.xml");
+    const REPORT_LATIN1: &[u8] = include_bytes!("../tests/fixtures/report_latin1.xml");
+
+    #[test]
+    fn strips_leading_bom_only() {
+        assert_eq!(strip_bom(REPORT_BOM), REPORT);
+        assert_eq!(strip_bom(REPORT), REPORT);
+    }
+
+    #[test]
+    fn decodes_latin1_report() {
+        assert_eq!(
+            declared_encoding(REPORT_LATIN1),
+            Some(encoding_rs::WINDOWS_1252)
+        );
+        let report = parse_report(REPORT_LATIN1).unwrap();
+        assert_eq!(
+            report.report_metadata.org_name,
+            "Société Générale Messagerie"
+        );
+        // Normalized reports are written as UTF-8
+        let xml = String::from_utf8(report.to_xml().unwrap()).unwrap();
+        assert!(xml.contains("<org_name>Société Générale Messagerie</org_name>"));
+    }
+
+    #[test]
+    fn retains_records_with_disposition() {
+        let mut report = parse_report(REPORT).unwrap();
+        let mut rejected = report.records[0].clone();
+        rejected.row.policy_evaluated.disposition = String::from(" Reject ");
+        rejected.row.source_ip = String::from("198.51.100.7");
+        report.records.push(rejected);
+
+        let mut filtered = report.clone();
+        filtered.retain_dispositions(&[String::from("quarantine"), String::from("reject")]);
+        assert_eq!(filtered.records.len(), 1);
+        assert_eq!(filtered.records[0].row.source_ip, "198.51.100.7");
+
+        report.retain_dispositions(&[String::from("quarantine")]);
+        assert!(report.records.is_empty());
+    }
+
+    #[test]
+    fn parses_all_fields() {
+        let report = parse_report(REPORT_FULL).unwrap();
+        assert_eq!(report.version.as_deref(), Some("1.0"));
+        let metadata = &report.report_metadata;
+        assert_eq!(
+            metadata.extra_contact_info.as_deref(),
+            Some("https://receiver.example/dmarc")
+        );
+        assert_eq!(metadata.errors.len(), 2);
+        let policy = &report.policy_published;
+        assert_eq!(policy.pct, Some(50));
+        assert_eq!(policy.fo.as_deref(), Some("1"));
+        assert_eq!(policy.np.as_deref(), Some("reject"));
+        let identifiers = &report.records[0].identifiers;
+        assert_eq!(identifiers.envelope_to.as_deref(), Some("receiver.example"));
+        assert_eq!(
+            identifiers.envelope_from.as_deref(),
+            Some("bounce.example.com")
+        );
+    }
+
+    #[test]
+    fn parses_interleaved_auth_results() {
+        // Some reporters alternate dkim and spf instead of grouping them
+        let report = parse_report(REPORT_INTERLEAVED).unwrap();
+        let auth_results = &report.records[0].auth_results;
+        let dkim: Vec<&str> = auth_results
+            .dkim
+            .iter()
+            .map(|r| r.domain.as_str())
+            .collect();
+        let spf: Vec<&str> = auth_results.spf.iter().map(|r| r.domain.as_str()).collect();
+        assert_eq!(dkim, ["example.com", "esp.example.net"]);
+        assert_eq!(spf, ["bounce.example.com", "example.com"]);
+        assert_eq!(auth_results.spf[1].scope.as_deref(), Some("helo"));
+    }
+
+    #[test]
+    fn round_trips_through_xml() {
+        for xml in [REPORT, REPORT_FULL, REPORT_INTERLEAVED] {
+            let report = parse_report(xml).unwrap();
+            let serialized = report.to_xml().unwrap();
+            assert_eq!(parse_report(&serialized).unwrap(), report);
+        }
+    }
+
+    #[test]
+    fn parses_report_with_bom() {
+        let report = parse_report(REPORT_BOM).unwrap();
+        assert_eq!(report.report_metadata.report_id, "11223344556677889900");
+        assert_eq!(report.policy_published.domain, "example.com");
+    }
+}
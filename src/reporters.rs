@@ -0,0 +1,58 @@
+use crate::report::Feedback;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default)]
+/// Reports received from one reporting organization
+struct Reporter {
+    reports: usize,
+    /// End of the most recent report period
+    last_end: i64,
+}
+
+#[derive(Debug, Default)]
+/// The reporting organizations that sent reports in this run
+pub struct Reporters {
+    reporters: BTreeMap<String, Reporter>,
+}
+
+impl Reporters {
+    pub fn add(&mut self, report: &Feedback) {
+        let metadata = &report.report_metadata;
+        let reporter = self.reporters.entry(metadata.org_name.clone()).or_default();
+        reporter.reports += 1;
+        reporter.last_end = reporter.last_end.max(metadata.date_range.end);
+    }
+
+    pub fn print(&self) {
+        // Prints the reporters with the most reports first
+
+        if self.reporters.is_empty() {
+            println!("No reports received.");
+            return;
+        }
+        let mut reporters: Vec<(&String, &Reporter)> = self.reporters.iter().collect();
+        reporters.sort_by_key(|(_, reporter)| std::cmp::Reverse(reporter.reports));
+
+        let width = reporters
+            .iter()
+            .map(|(name, _)| name.len())
+            .chain(std::iter::once("Reporter".len()))
+            .max()
+            .unwrap_or_default();
+        println!(
+            "{:<width$}  {:>7}  Most recent report",
+            "Reporter",
+            "Reports",
+            width = width
+        );
+        for (name, reporter) in reporters {
+            println!(
+                "{:<width$}  {:>7}  {}",
+                name,
+                reporter.reports,
+                crate::format_timestamp(reporter.last_end),
+                width = width
+            );
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use libxml::error::StructuredError;
+use libxml::parser::Parser;
+use libxml::schemas::{SchemaParserContext, SchemaValidationContext};
+
+/// XSD of the aggregate reports from RFC 7489, appendix C
+const SCHEMA: &str = include_str!("rua.xsd");
+
+/// Target namespace of the XSD, most reporters don't declare it
+const NAMESPACE: &str = "http://dmarc.org/dmarc-xml/0.1";
+
+pub fn validate(xml: &[u8]) -> Vec<String> {
+    // Validates the report against the XSD and returns the violations. Reports without the
+    // namespace are validated against the XSD without its target namespace, otherwise none of
+    // their elements would be declared.
+
+    let document = match Parser::default().parse_string(xml) {
+        Ok(document) => document,
+        Err(e) => return vec![format!("not well-formed XML: {}", e)],
+    };
+    let namespaced = document
+        .get_root_element()
+        .and_then(|root| root.get_namespace())
+        .is_some_and(|namespace| namespace.get_href() == NAMESPACE);
+    let schema = if namespaced {
+        String::from(SCHEMA)
+    } else {
+        SCHEMA
+            .replace(&format!("targetNamespace=\"{}\"", NAMESPACE), "")
+            .replace(&format!("xmlns=\"{}\"", NAMESPACE), "")
+    };
+
+    // The parser keeps a pointer to the buffer, it has to outlive the validation
+    let mut parser = SchemaParserContext::from_buffer(schema.as_bytes());
+    let mut context = match SchemaValidationContext::from_parser(&mut parser) {
+        Ok(context) => context,
+        Err(errors) => panic!("Bundled XSD is invalid: {}", describe(&errors).join(", ")),
+    };
+    match context.validate_document(&document) {
+        Ok(()) => Vec::new(),
+        Err(errors) => describe(&errors),
+    }
+}
+
+fn describe(errors: &[StructuredError]) -> Vec<String> {
+    errors
+        .iter()
+        .filter_map(|error| {
+            let message = error.message.as_deref()?.trim().trim_end_matches('.');
+            Some(match error.line {
+                Some(line) => format!("line {}: {}", line, message),
+                None => String::from(message),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_violations_of_malformed_report() {
+        let problems = validate(include_bytes!("../tests/fixtures/report_malformed.xml"));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert_eq!(
+            problems[0],
+            "line 6: Element 'extra_contact_info': This element is not expected. Expected is ( email )"
+        );
+        assert!(problems[1]
+            .starts_with("line 19: Element 'p': [facet 'enumeration'] The value 'block'"));
+        assert!(problems[2]
+            .starts_with("line 26: Element 'source_ip': [facet 'pattern'] The value '198.51.100'"));
+    }
+
+    #[test]
+    fn accepts_report_with_and_without_namespace() {
+        // np is only defined by the successor of RFC 7489
+        let report = String::from_utf8_lossy(include_bytes!("../tests/fixtures/report_full.xml"))
+            .replace("    <np>reject</np>\n", "");
+        assert_eq!(validate(report.as_bytes()), Vec::<String>::new());
+        let namespaced = report.replacen(
+            "<feedback>",
+            &format!("<feedback xmlns=\"{}\">", NAMESPACE),
+            1,
+        );
+        assert_eq!(validate(namespaced.as_bytes()), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Destination of the extracted reports
+///
+/// The DMARC, TLS and forensic reports, the merged ones and the quarantined ones are written
+/// through a sink, which `write_report` and `write_output` in main.rs pick from the options. The
+/// state, the manifest, the error log, the enrichment cache and the messages saved with
+/// --save-eml are written to their files directly. Other destinations for the reports can
+/// implement it, e.g. to keep them in memory:
+///
+/// ```ignore
+/// struct MemorySink(Vec<(String, Vec<u8>)>);
+///
+/// impl ReportSink for MemorySink {
+///     fn write_report(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+///         self.0.push((name.to_string(), bytes.to_vec()));
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait ReportSink {
+    fn write_report(&mut self, name: &str, bytes: &[u8]) -> Result<()>;
+}
+
+/// Writes the reports as files to a directory
+pub struct FileSink<'a> {
+    path: &'a Path,
+    /// Directory for the temporary files, the target directory if not given
+    temp_dir: Option<&'a Path>,
+}
+
+impl<'a> FileSink<'a> {
+    pub fn new(path: &'a Path, temp_dir: Option<&'a Path>) -> Self {
+        FileSink { path, temp_dir }
+    }
+}
+
+impl ReportSink for FileSink<'_> {
+    fn write_report(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        // Writes to a temporary file first and renames it, so there are never partial reports
+
+        let filepath = self.path.join(name);
+        let temp_path = self
+            .temp_dir
+            .unwrap_or(self.path)
+            .join(format!(".{}.tmp", name));
+        let mut file = File::create(&temp_path)
+            .map_err(|e| anyhow!("Could not create file {}: {}", temp_path.display(), e))?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+
+        if std::fs::rename(&temp_path, &filepath).is_err() {
+            // Renaming fails across file systems
+            let copied = std::fs::copy(&temp_path, &filepath);
+            std::fs::remove_file(&temp_path)?;
+            copied.map_err(|e| anyhow!("Could not write file {}: {}", filepath.display(), e))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the reports to stdout, used for a single report with --output-stdout
+pub struct StdoutSink;
+
+impl ReportSink for StdoutSink {
+    fn write_report(&mut self, _name: &str, bytes: &[u8]) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(bytes)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use crate::report::Feedback;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default)]
+/// Failing records sent from one source IP
+struct Source {
+    records: usize,
+    messages: u64,
+}
+
+#[derive(Debug, Default)]
+/// Source IPs of the records that failed DMARC, likely spoofing the domains
+pub struct Sources {
+    sources: BTreeMap<String, Source>,
+}
+
+impl Sources {
+    pub fn add(&mut self, report: &Feedback) {
+        // A record fails if neither DKIM nor SPF passed, or if the receiver didn't deliver the
+        // messages normally
+
+        for record in &report.records {
+            let evaluated = &record.row.policy_evaluated;
+            let failed = evaluated.dkim.as_deref() != Some("pass")
+                && evaluated.spf.as_deref() != Some("pass");
+            if !failed && evaluated.disposition.trim() == "none" {
+                continue;
+            }
+            let source = self
+                .sources
+                .entry(record.row.source_ip.trim().to_string())
+                .or_default();
+            source.records += 1;
+            source.messages += record.row.count;
+        }
+    }
+
+    pub fn print(&self, limit: usize) {
+        // Prints the sources with the most failing messages first
+
+        if self.sources.is_empty() {
+            println!("No failing records received.");
+            return;
+        }
+        let mut sources: Vec<(&String, &Source)> = self.sources.iter().collect();
+        sources.sort_by_key(|(_, source)| std::cmp::Reverse(source.messages));
+        sources.truncate(limit);
+
+        let width = sources
+            .iter()
+            .map(|(ip, _)| ip.len())
+            .chain(std::iter::once("Source IP".len()))
+            .max()
+            .unwrap_or_default();
+        println!(
+            "{:<width$}  {:>8}  {:>7}",
+            "Source IP",
+            "Messages",
+            "Records",
+            width = width
+        );
+        for (ip, source) in sources {
+            println!(
+                "{:<width$}  {:>8}  {:>7}",
+                ip,
+                source.messages,
+                source.records,
+                width = width
+            );
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+/// Information persisted between runs to only process new messages
+pub struct State {
+    /// Watermarks of each folder, keyed by the selected folder name
+    #[serde(default)]
+    pub folders: BTreeMap<String, FolderState>,
+    /// Unix timestamp of the start of the last run that finished without errors
+    pub last_success: Option<i64>,
+    /// Reports extracted within --dedupe-window, keyed by report id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reports: BTreeMap<String, KnownReport>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A report extracted in an earlier run
+pub struct KnownReport {
+    /// Hash of the report as compared by --dedup-mode, in hex
+    pub hash: String,
+    /// Unix timestamp of the run that extracted it first
+    pub seen: i64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+/// Watermarks of a single folder
+pub struct FolderState {
+    /// UIDVALIDITY of the mailbox when the state was last written
+    pub uid_validity: Option<u32>,
+    /// Highest UID that has been processed
+    pub last_uid: Option<u32>,
+    /// Unix timestamp of the newest message Date that has been seen
+    pub last_date: Option<i64>,
+    /// HIGHESTMODSEQ of the mailbox after the last complete run, with CONDSTORE or QRESYNC
+    pub highest_modseq: Option<u64>,
+    /// UIDs above last_uid that have been processed, e.g. the newest messages with
+    /// --newest-first and --limit while older ones are left for the next run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub processed_uids: Vec<u32>,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<State> {
+        // Loads the state file, a missing file results in an empty state
+
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn folder(&mut self, name: &str) -> &mut FolderState {
+        self.folders.entry(String::from(name)).or_default()
+    }
+
+    pub fn prune_reports(&mut self, now: i64, window_days: u32) {
+        // Forgets the reports extracted before the window, so the state file doesn't grow with
+        // every report ever received
+
+        let oldest = now - window_days as i64 * 86400;
+        self.reports.retain(|_, report| report.seen >= oldest);
+    }
+}
+
+impl FolderState {
+    pub fn check_uid_validity(&mut self, uid_validity: Option<u32>) -> bool {
+        // Compares the UIDVALIDITY of the mailbox with the stored one. If it changed, the stored
+        // UIDs are no longer valid and the watermark is reset. Returns false in that case.
+
+        let valid = match (self.uid_validity, uid_validity) {
+            (Some(stored), Some(current)) => stored == current,
+            _ => true,
+        };
+        if !valid {
+            self.last_uid = None;
+            self.processed_uids.clear();
+        }
+        self.uid_validity = uid_validity;
+        valid
+    }
+
+    pub fn add_processed(&mut self, uids: impl IntoIterator<Item = u32>) {
+        // Remembers the processed UIDs that the watermark doesn't cover, the ones below it are
+        // dropped
+
+        let last_uid = self.last_uid.unwrap_or(0);
+        self.processed_uids.extend(uids);
+        self.processed_uids.retain(|uid| *uid > last_uid);
+        self.processed_uids.sort_unstable();
+        self.processed_uids.dedup();
+    }
+
+    pub fn cover_processed(&mut self) {
+        // Once no older message is left, the watermark is moved above the processed UIDs
+
+        if let Some(uid) = self.processed_uids.last() {
+            self.last_uid = Some(self.last_uid.map_or(*uid, |last| last.max(*uid)));
+        }
+        self.processed_uids.clear();
+    }
+
+    pub fn is_processed(&self, uid: u32) -> bool {
+        self.processed_uids.binary_search(&uid).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_reports_outside_of_window() {
+        let json = r#"{
+            "folders": {},
+            "last_success": null,
+            "reports": {
+                "old": {"hash": "0000000000000001", "seen": 1000000},
+                "recent": {"hash": "0000000000000002", "seen": 1500000}
+            }
+        }"#;
+        let mut state: State = serde_json::from_str(json).unwrap();
+        state.prune_reports(1000000 + 7 * 86400, 7);
+        assert_eq!(state.reports.len(), 2);
+        state.prune_reports(1500000 + 3 * 86400, 7);
+        assert_eq!(state.reports.keys().collect::<Vec<_>>(), ["recent"]);
+        state.prune_reports(1500000 + 8 * 86400, 7);
+        assert!(state.reports.is_empty());
+        assert!(!serde_json::to_string(&state).unwrap().contains("reports"));
+    }
+
+    #[test]
+    fn uid_validity_change_resets_watermark() {
+        let mut state = FolderState {
+            uid_validity: Some(1),
+            last_uid: Some(42),
+            ..Default::default()
+        };
+        assert!(!state.check_uid_validity(Some(2)));
+        assert_eq!(state.last_uid, None);
+        assert_eq!(state.uid_validity, Some(2));
+    }
+
+    #[test]
+    fn keeps_processed_uids_above_watermark() {
+        let mut state = FolderState {
+            uid_validity: Some(1),
+            last_uid: Some(4),
+            ..Default::default()
+        };
+        state.add_processed(vec![9, 3, 10, 9]);
+        assert_eq!(state.processed_uids, [9, 10]);
+        assert!(state.is_processed(10) && !state.is_processed(5));
+
+        state.last_uid = Some(9);
+        state.add_processed(vec![7]);
+        assert_eq!(state.processed_uids, [10]);
+        state.cover_processed();
+        assert_eq!(state.last_uid, Some(10));
+        state.add_processed(vec![12]);
+        assert!(!state.check_uid_validity(Some(2)));
+        assert!(state.processed_uids.is_empty());
+    }
+
+    #[test]
+    fn same_uid_validity_keeps_watermark() {
+        let mut state = FolderState {
+            uid_validity: Some(1),
+            last_uid: Some(42),
+            ..Default::default()
+        };
+        assert!(state.check_uid_validity(Some(1)));
+        assert_eq!(state.last_uid, Some(42));
+    }
+
+    #[test]
+    fn first_run_stores_uid_validity() {
+        let mut state = FolderState::default();
+        assert!(state.check_uid_validity(Some(7)));
+        assert_eq!(state.uid_validity, Some(7));
+        assert_eq!(state.last_uid, None);
+    }
+}
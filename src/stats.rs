@@ -0,0 +1,166 @@
+use crate::report::Feedback;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default)]
+/// Aggregated results of all reports for one domain
+pub struct DomainStats {
+    pub reports: usize,
+    /// Number of messages covered by the reports
+    pub messages: u64,
+    /// Messages that passed DMARC, i.e. DKIM or SPF aligned
+    pub passed: u64,
+    pub dkim_passed: u64,
+    pub spf_passed: u64,
+}
+
+#[derive(Debug, Default)]
+/// Records whose disposition was overridden for one reason type, e.g. forwarded
+pub struct ReasonStats {
+    pub records: usize,
+    pub messages: u64,
+}
+
+#[derive(Debug, Default)]
+/// Pass/fail statistics of all processed reports, per domain
+pub struct Statistics {
+    domains: BTreeMap<String, DomainStats>,
+    /// Policy override reasons over all domains
+    reasons: BTreeMap<String, ReasonStats>,
+}
+
+impl Statistics {
+    pub fn add(&mut self, report: &Feedback) {
+        let stats = self
+            .domains
+            .entry(report.policy_published.domain.to_lowercase())
+            .or_default();
+        stats.reports += 1;
+
+        for record in &report.records {
+            let count = record.row.count;
+            let evaluated = &record.row.policy_evaluated;
+            let dkim = evaluated.dkim.as_deref() == Some("pass");
+            let spf = evaluated.spf.as_deref() == Some("pass");
+
+            stats.messages += count;
+            if dkim || spf {
+                stats.passed += count;
+            }
+            if dkim {
+                stats.dkim_passed += count;
+            }
+            if spf {
+                stats.spf_passed += count;
+            }
+            for reason in &evaluated.reasons {
+                let reason = self
+                    .reasons
+                    .entry(reason.kind.trim().to_lowercase())
+                    .or_default();
+                reason.records += 1;
+                reason.messages += count;
+            }
+        }
+    }
+
+    /// Messages over all domains and how many of them passed DKIM or SPF
+    #[cfg(feature = "serve")]
+    pub fn totals(&self) -> (u64, u64) {
+        self.domains
+            .values()
+            .fold((0, 0), |(messages, passed), stats| {
+                (messages + stats.messages, passed + stats.passed)
+            })
+    }
+
+    pub fn print(&self) {
+        let width = self
+            .domains
+            .keys()
+            .map(String::len)
+            .chain(std::iter::once("Domain".len()))
+            .max()
+            .unwrap_or_default();
+
+        println!(
+            "{:<width$}  {:>7}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}",
+            "Domain",
+            "Reports",
+            "Messages",
+            "Passed",
+            "Failed",
+            "DKIM",
+            "SPF",
+            width = width
+        );
+        for (domain, stats) in &self.domains {
+            println!(
+                "{:<width$}  {:>7}  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}",
+                domain,
+                stats.reports,
+                stats.messages,
+                stats.passed,
+                stats.messages - stats.passed,
+                stats.dkim_passed,
+                stats.spf_passed,
+                width = width
+            );
+        }
+
+        if !self.reasons.is_empty() {
+            println!();
+            print!("{}", self.reasons_table());
+        }
+    }
+
+    fn reasons_table(&self) -> String {
+        // Overrides explain why messages were delivered or rejected despite the DMARC result
+
+        let width = self
+            .reasons
+            .keys()
+            .map(String::len)
+            .chain(std::iter::once("Override reason".len()))
+            .max()
+            .unwrap_or_default();
+
+        let mut table = format!(
+            "{:<width$}  {:>7}  {:>8}\n",
+            "Override reason",
+            "Records",
+            "Messages",
+            width = width
+        );
+        for (reason, stats) in &self.reasons {
+            table.push_str(&format!(
+                "{:<width$}  {:>7}  {:>8}\n",
+                reason,
+                stats.records,
+                stats.messages,
+                width = width
+            ));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::parse_report;
+
+    #[test]
+    fn summarizes_override_reasons() {
+        let report = parse_report(include_bytes!("../tests/fixtures/report_reasons.xml")).unwrap();
+        let mut statistics = Statistics::default();
+        statistics.add(&report);
+        assert_eq!(
+            statistics.reasons_table(),
+            "Override reason  Records  Messages\n\
+             forwarded              2         5\n\
+             local_policy           1         2\n\
+             mailing_list           1         2\n"
+        );
+        assert_eq!(statistics.domains["example.com"].passed, 10);
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{anyhow, Result};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use native_tls::{TlsConnector, TlsStream};
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::rc::Rc;
+
+const RAW_TAG: &str = "raw";
+
+/// X509_V_ERR_CERT_HAS_EXPIRED of OpenSSL
+#[cfg(not(any(windows, target_vendor = "apple")))]
+const CERT_HAS_EXPIRED: i32 = 10;
+
+/// Sets up the TLS connections to the server
+pub enum Connector {
+    Native(TlsConnector),
+    /// OpenSSL is used directly to accept recently expired certificates for --tls-expired-grace
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    OpenSsl(openssl::ssl::SslConnector),
+}
+
+impl Connector {
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    pub fn with_expired_grace(grace_days: u32) -> Result<Connector> {
+        // Only the expiry of the server certificate itself is forgiven, every other check of
+        // the chain and the host name still has to pass
+
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.set_verify_callback(SslVerifyMode::PEER, move |verified, context| {
+            if verified {
+                return true;
+            }
+            if context.error().as_raw() != CERT_HAS_EXPIRED || context.error_depth() != 0 {
+                return false;
+            }
+            let not_after = match context.current_cert().and_then(|cert| unix_time(cert.not_after())) {
+                Some(not_after) => not_after,
+                None => return false,
+            };
+            let now = chrono::Utc::now().timestamp();
+            if !within_grace(not_after, now, grace_days) {
+                return false;
+            }
+            eprintln!(
+                "WARNING: The certificate of the server expired {} days ago! It is only accepted because of --tls-expired-grace {}.",
+                (now - not_after) / 86400,
+                grace_days
+            );
+            true
+        });
+        Ok(Connector::OpenSsl(builder.build()))
+    }
+
+    pub fn connect(&self, domain: &str, tcp: TcpStream) -> Result<SharedStream> {
+        let stream = match self {
+            Connector::Native(connector) => Tls::Native(connector.connect(domain, tcp)?),
+            #[cfg(not(any(windows, target_vendor = "apple")))]
+            Connector::OpenSsl(connector) => Tls::OpenSsl(
+                connector
+                    .connect(domain, tcp)
+                    .map_err(|e| anyhow!("TLS handshake failed: {}", e))?,
+            ),
+        };
+        Ok(SharedStream::new(stream))
+    }
+}
+
+#[cfg(not(any(windows, target_vendor = "apple")))]
+fn unix_time(time: &openssl::asn1::Asn1TimeRef) -> Option<i64> {
+    let epoch = openssl::asn1::Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(time).ok()?;
+    Some(diff.days as i64 * 86400 + diff.secs as i64)
+}
+
+/// Whether a certificate that is valid until not_after expired at most grace_days before now
+#[cfg(not(any(windows, target_vendor = "apple")))]
+fn within_grace(not_after: i64, now: i64, grace_days: u32) -> bool {
+    now - not_after <= grace_days as i64 * 86400
+}
+
+#[derive(Debug)]
+enum Tls {
+    Native(TlsStream<TcpStream>),
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    OpenSsl(openssl::ssl::SslStream<TcpStream>),
+}
+
+impl Read for Tls {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Tls::Native(stream) => stream.read(buf),
+            #[cfg(not(any(windows, target_vendor = "apple")))]
+            Tls::OpenSsl(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Tls {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Tls::Native(stream) => stream.write(buf),
+            #[cfg(not(any(windows, target_vendor = "apple")))]
+            Tls::OpenSsl(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Tls::Native(stream) => stream.flush(),
+            #[cfg(not(any(windows, target_vendor = "apple")))]
+            Tls::OpenSsl(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Connection to the IMAP server that is shared between the imap session and raw commands
+///
+/// The imap crate fails on untagged responses it can't parse (e.g. NAMESPACE), so these
+/// commands are sent and read directly on the stream.
+pub struct SharedStream(Rc<RefCell<Connection>>);
+
+#[derive(Debug)]
+struct Connection {
+    stream: Tls,
+    deflate: Option<Deflate>,
+}
+
+#[derive(Debug)]
+/// State of the COMPRESS=DEFLATE extension (RFC 4978)
+struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed data read from the stream that is not decompressed yet
+    input: Vec<u8>,
+    position: usize,
+}
+
+impl SharedStream {
+    fn new(stream: Tls) -> SharedStream {
+        SharedStream(Rc::new(RefCell::new(Connection {
+            stream,
+            deflate: None,
+        })))
+    }
+
+    pub fn raw_command(&self, command: &str) -> Result<Vec<String>> {
+        // Runs the command and returns the untagged response lines. Must only be called while
+        // the imap session is not waiting for a response.
+
+        let mut stream = self.0.borrow_mut();
+        write!(stream, "{} {}\r\n", RAW_TAG, command)?;
+        stream.flush()?;
+
+        let mut lines = Vec::new();
+        loop {
+            let line = read_line(&mut *stream)?;
+            if let Some(status) = line.strip_prefix(RAW_TAG) {
+                let status = status.trim_start();
+                if status.starts_with("OK") {
+                    return Ok(lines);
+                }
+                return Err(anyhow!("{} failed: {}", command, status));
+            }
+            lines.push(line);
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.0.borrow().deflate.is_some()
+    }
+
+    pub fn enable_compression(&self) -> Result<()> {
+        // Everything after the response to COMPRESS is deflate compressed in both directions
+
+        self.raw_command("COMPRESS DEFLATE")?;
+        self.0.borrow_mut().deflate = Some(Deflate {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            input: Vec::new(),
+            position: 0,
+        });
+        Ok(())
+    }
+}
+
+fn read_line(stream: &mut impl Read) -> Result<String> {
+    // Reads byte by byte, so nothing after the line is consumed from the stream
+
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(anyhow!("Connection lost"));
+        }
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deflate = match &mut self.deflate {
+            Some(deflate) => deflate,
+            None => return self.stream.read(buf),
+        };
+        loop {
+            // Output still pending in the decompressor has to be returned before reading more
+            let total_in = deflate.decompress.total_in();
+            let total_out = deflate.decompress.total_out();
+            deflate
+                .decompress
+                .decompress(
+                    &deflate.input[deflate.position..],
+                    buf,
+                    FlushDecompress::Sync,
+                )
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            deflate.position += (deflate.decompress.total_in() - total_in) as usize;
+
+            let len = (deflate.decompress.total_out() - total_out) as usize;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+
+            if deflate.position == deflate.input.len() {
+                let mut input = [0; 4096];
+                let len = self.stream.read(&mut input)?;
+                if len == 0 {
+                    return Ok(0);
+                }
+                deflate.input = input[..len].to_vec();
+                deflate.position = 0;
+            }
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let deflate = match &mut self.deflate {
+            Some(deflate) => deflate,
+            None => return self.stream.write(buf),
+        };
+
+        // The output is flushed for every write, the server has to see complete commands
+        let total_in = deflate.compress.total_in();
+        let mut output = Vec::with_capacity(buf.len() + 64);
+        loop {
+            let consumed = (deflate.compress.total_in() - total_in) as usize;
+            deflate
+                .compress
+                .compress_vec(&buf[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let consumed = (deflate.compress.total_in() - total_in) as usize;
+            if consumed == buf.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(1024);
+        }
+        self.stream.write_all(&output)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+#[cfg(all(test, not(any(windows, target_vendor = "apple"))))]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86400;
+
+    #[test]
+    fn accepts_expiry_within_grace() {
+        let now = 1_700_000_000;
+        assert!(within_grace(now - 3 * DAY, now, 7));
+        assert!(within_grace(now - 7 * DAY, now, 7));
+        assert!(within_grace(now - DAY, now, 7));
+    }
+
+    #[test]
+    fn rejects_expiry_beyond_grace() {
+        let now = 1_700_000_000;
+        assert!(!within_grace(now - 7 * DAY - 1, now, 7));
+        assert!(!within_grace(now - DAY, now, 0));
+    }
+
+    #[test]
+    fn converts_certificate_time() {
+        let time = openssl::asn1::Asn1Time::from_unix(1_700_000_000).unwrap();
+        assert_eq!(unix_time(&time), Some(1_700_000_000));
+    }
+}
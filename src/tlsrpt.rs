@@ -0,0 +1,45 @@
+use crate::report::strip_bom;
+use anyhow::Result;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// SMTP TLS report as defined in RFC 8460, section 4
+pub struct TlsRpt {
+    pub organization_name: String,
+    pub date_range: DateRange,
+    pub report_id: String,
+    #[serde(default)]
+    pub policies: Vec<PolicyResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DateRange {
+    pub start_datetime: String,
+    pub end_datetime: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyResult {
+    pub policy: Policy,
+    pub summary: Summary,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Policy {
+    pub policy_type: String,
+    pub policy_domain: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    pub total_successful_session_count: u64,
+    pub total_failure_session_count: u64,
+}
+
+pub fn parse_tlsrpt(json: &[u8]) -> Result<TlsRpt> {
+    Ok(serde_json::from_slice(strip_bom(json))?)
+}